@@ -3,7 +3,13 @@ A Sender or SyncSender is used to send data to a Receiver.
 Both senders are clone-able (multi-producer) such that 
 many threads can send simultaneously to one receiver (single-consumer).
 */
-use std::{sync::mpsc::{Sender, channel}};
+use std::{panic::{self, AssertUnwindSafe}, sync::{Arc, Mutex, mpsc::{Sender, Receiver, channel}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+// number of worker threads of the default executor
+pub const WORKERS: usize = 4;
+
+// a job is any closure that can be moved to a worker thread and run once
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
 
 /* 
 Automatically generate clone method without manual implementation
@@ -15,43 +21,145 @@ Sender can be cloned
 #[derive(Clone)]
 pub struct Executor {
     task_sender: Sender<Task>,
+    // the workers are shared by all clones, so any clone can shut them down
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
 }
 
 // enumeration type for tasks
 pub enum Task {
     Println(String),
+    Run(Job),
     Exit,
 }
 
+// handle to the result of a spawned job
+pub struct Handle<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Handle<T> {
+    // wait for the job to finish, None if it panicked or was never run
+    pub fn join(self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+
+    // the result if the job is done, without waiting
+    pub fn try_join(&self) -> Option<T> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Executor {
     // constructor of Executor
     pub fn new() -> Self {
+        Self::with_workers(WORKERS)
+    }
+
+    // constructor with a fixed number of worker threads
+    pub fn with_workers(size: usize) -> Self {
         // format: pub fn channel<T>() -> (Sender<T>, Receiver<T>)
         let (sender, receiver) = channel();
-        std::thread::spawn(move || {
-            loop {
-                match receiver.recv() {
-                    // when using match
-                    // x => y conducts y when x is matched
-                    Ok(task) => {
-                        match task {
-                            Task::Println(string) => println!("{}", string),
-                            Task::Exit => return
-                        }
-                    },
-                    Err(_) => {
-                        return;
+        // workers take turns to receive from the same channel
+        let receiver: Arc<Mutex<Receiver<Task>>> = Arc::new(Mutex::new(receiver));
+        let workers = (0..size.max(1)).map(|_| {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                loop {
+                    // release the lock before running the task so other workers can receive
+                    let task = receiver.lock().unwrap().recv();
+                    match task {
+                        // when using match
+                        // x => y conducts y when x is matched
+                        Ok(Task::Println(string)) => println!("{}", string),
+                        // a panicking job must not take the worker down with it
+                        Ok(Task::Run(job)) => panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_default(),
+                        Ok(Task::Exit) | Err(_) => return,
                     }
                 }
-            }
-        });
+            })
+        }).collect();
         // return the Executor
-        Executor { task_sender: sender }
+        Executor { task_sender: sender, workers: Arc::new(Mutex::new(workers)) }
     }
+
     // print func for Executor
     pub fn println(&self, string: String) {
         // .send() attempts to send a value on this channel, returning it back if it could not be sent.
         self.task_sender.send(Task::Println(string)).unwrap()
     }
+
+    // run a job on the pool, its return value is sent back through the handle
+    // after shutdown the job is dropped and the handle never gets a result
+    pub fn spawn<T, F>(&self, job: F) -> Handle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = channel();
+        let task = Task::Run(Box::new(move || {
+            // the handle might have been dropped, the result is then not needed
+            sender.send(job()).unwrap_or_default();
+        }));
+        self.task_sender.send(task).unwrap_or_default();
+        Handle { receiver }
+    }
+
+    // let the workers finish the queued jobs, then stop them and wait until they exit, at most for the timeout
+    // returns whether all of them exited, a worker stuck on a hung job is left behind rather than holding up the caller
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut workers = self.workers.lock().unwrap();
+        for _ in workers.iter() {
+            self.task_sender.send(Task::Exit).unwrap_or_default();
+        }
+        while workers.iter().any(|worker| !worker.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let exited = workers.iter().all(|worker| worker.is_finished());
+        for worker in workers.drain(..).filter(|worker| worker.is_finished()) {
+            worker.join().unwrap_or_default();
+        }
+        exited
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_runs_the_queued_jobs() {
+        let executor = Executor::with_workers(2);
+        let handles: Vec<Handle<usize>> = (0..10).map(|i| executor.spawn(move || i * 2)).collect();
+        assert!(executor.shutdown(Duration::from_secs(5)));
+        assert_eq!(handles.into_iter().map(|handle| handle.join().unwrap()).sum::<usize>(), 90);
+        // the pool is gone, later jobs are dropped
+        assert_eq!(executor.spawn(|| 1).join(), None);
+    }
+
+    #[test]
+    fn shutdown_gives_up_on_a_hung_job() {
+        let executor = Executor::with_workers(2);
+        let (release, hung) = channel::<()>();
+        executor.spawn(move || hung.recv().unwrap_or_default());
+        let start = Instant::now();
+        assert!(!executor.shutdown(Duration::from_millis(100)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn a_panicking_job_does_not_take_its_worker_down() {
+        let executor = Executor::with_workers(1);
+        let panicked = executor.spawn(|| -> usize { panic!("job failed") });
+        assert_eq!(panicked.join(), None);
+        assert_eq!(executor.spawn(|| 7).join(), Some(7));
+        assert!(executor.shutdown(Duration::from_secs(5)));
+    }
+}
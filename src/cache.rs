@@ -0,0 +1,113 @@
+/*
+Cache of the last successful quote per code, so the app starts with last-known prices.
+The file looks like {"quotes":{"0600000":{"title":..,"price":..,..,"time":..,"saved":..}}}
+*/
+use std::{collections::HashMap, fs, path::Path};
+
+use chrono::{DateTime, FixedOffset};
+use serde::{Serialize, Deserialize};
+
+use crate::{DynResult, Stock, store};
+
+pub const CACHE_PATH: &str=".stocks_cache.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedQuote {
+    #[serde(flatten)]
+    pub stock: Stock,
+    // quote time on the exchange clock, if the feed sent one
+    pub time: Option<DateTime<FixedOffset>>,
+    // when the quote was written to the cache
+    pub saved: DateTime<FixedOffset>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CacheFile {
+    quotes: HashMap<String, CachedQuote>,
+}
+
+// load the cached quotes by code, empty if there is no cache yet
+pub fn load(path: &Path) -> HashMap<String, CachedQuote> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    serde_json::from_str::<CacheFile>(&content).unwrap_or_default().quotes
+}
+
+// write the live quotes into the cache at the given time, keeping the cached ones of other codes
+// the TUI and status-line both save, so the file is locked while merging and replaced at once
+pub fn save(path: &Path, stocks: &[Stock], saved: DateTime<FixedOffset>) -> DynResult {
+    let _lock = store::lock(path, true)?;
+    let mut quotes = load(path);
+    // stocks without a live quote yet would overwrite good data with zeros
+    for stock in stocks.iter().filter(|stock| !stock.cached && stock.price != 0.0) {
+        quotes.insert(stock.code.clone(), CachedQuote { stock: stock.clone(), time: stock.time, saved });
+    }
+    store::write_atomic(path, serde_json::to_string(&CacheFile { quotes })?.as_bytes())?;
+    Ok(())
+}
+
+// fill the stocks with their cached quotes and mark them as cached until live data arrives
+pub fn apply(stocks: &mut [Stock], quotes: &HashMap<String, CachedQuote>) {
+    for stock in stocks.iter_mut() {
+        if let Some(quote) = quotes.get(&stock.code) {
+            let q = &quote.stock;
+            stock.title = q.title.clone();
+            stock.price = q.price;
+            stock.percent = q.percent;
+            stock.open = q.open;
+            stock.yestclose = q.yestclose;
+            stock.high = q.high;
+            stock.low = q.low;
+            stock.volume = q.volume;
+            stock.time = quote.time;
+            stock.cached = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar;
+    use chrono::TimeZone;
+
+    fn live(code: &str, price: f64) -> Stock {
+        let mut stock = Stock::new(code);
+        stock.price = price;
+        stock
+    }
+
+    #[test]
+    fn saves_keep_the_quotes_of_other_codes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        let saved = calendar::exchange_tz().with_ymd_and_hms(2024, 7, 10, 10, 0, 0).unwrap();
+        save(&path, &[live("0600000", 7.5), live("1000001", 0.0)], saved).unwrap();
+        let mut cached = live("0600000", 7.0);
+        cached.cached = true;
+        save(&path, &[live("1000001", 11.2), cached], saved).unwrap();
+        let quotes = load(&path);
+        assert_eq!((quotes["0600000"].stock.price, quotes["1000001"].stock.price), (7.5, 11.2));
+        assert!(!dir.path().join("cache.json.tmp").exists());
+    }
+
+    #[test]
+    fn concurrent_saves_leave_a_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        let saved = calendar::exchange_tz().with_ymd_and_hms(2024, 7, 10, 10, 0, 0).unwrap();
+        let threads: Vec<_> = (0..8).map(|i| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    save(&path, &[live(&format!("06000{:02}", i), 1.0 + i as f64)], saved).unwrap();
+                    // a reader never sees a half written file, and no code is lost to a concurrent save
+                    assert!(!load(&path).is_empty());
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(load(&path).len(), 8);
+    }
+}
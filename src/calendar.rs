@@ -0,0 +1,232 @@
+/*
+Trading calendar of the Shanghai and Shenzhen stock exchanges.
+Both exchanges share the same sessions in Asia/Shanghai time:
+    09:15-09:30 pre-open call auction
+    09:30-11:30 morning session
+    11:30-13:00 lunch break
+    13:00-15:00 afternoon session
+They are closed on weekends and on public holidays, which are loaded from a file.
+*/
+use std::{collections::HashSet, fs, io, path::Path, sync::Arc};
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use serde_json::{Map, Value, json};
+
+use crate::DynResult;
+
+pub const HOLIDAYS_PATH: &str=".stocks_holidays.json";
+
+// Asia/Shanghai has no daylight saving time, so a fixed offset is enough
+pub fn exchange_tz() -> FixedOffset {
+    FixedOffset::east_opt(8 * 3600).unwrap()
+}
+
+// current time on the exchange clock
+pub fn exchange_now() -> DateTime<FixedOffset> {
+    Utc::now().with_timezone(&exchange_tz())
+}
+
+// where the App gets the time from, the system clock unless a test or a replay drives it
+pub type Clock = Arc<dyn Fn() -> DateTime<FixedOffset> + Send + Sync>;
+
+pub fn system_clock() -> Clock {
+    Arc::new(exchange_now)
+}
+
+// a clock stopped at the given time
+pub fn fixed_clock(time: DateTime<FixedOffset>) -> Clock {
+    Arc::new(move || time)
+}
+
+// Define phases of a trading day as enum types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    PreOpen,
+    Morning,
+    Lunch,
+    Afternoon,
+    Closed,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::PreOpen => "PRE-OPEN",
+            Phase::Morning => "MORNING",
+            Phase::Lunch => "LUNCH",
+            Phase::Afternoon => "AFTERNOON",
+            Phase::Closed => "CLOSED",
+        }
+    }
+
+    // quotes only change in these phases, so only these are worth refreshing
+    pub fn is_trading(&self) -> bool {
+        matches!(self, Phase::PreOpen | Phase::Morning | Phase::Afternoon)
+    }
+}
+
+fn hm(hour: u32, min: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+}
+
+#[derive(Default)]
+pub struct Calendar {
+    pub holidays: HashSet<NaiveDate>,
+}
+
+impl Calendar {
+    // load holidays from a .json file like {"holidays":["2024-10-01", ...]}
+    // a missing file means there are no holidays, a bad file is reported along with the holidays read from it
+    pub fn load(path: &Path) -> (Self, Option<String>) {
+        let mut calendar = Self::default();
+        let err = match fs::read_to_string(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => Some(err.to_string()),
+            Ok(content) => calendar.parse(&content).err().map(|err| err.to_string()),
+        };
+        (calendar, err.map(|err| format!("{}: {}", path.display(), err)))
+    }
+
+    // add the holidays listed in a .json string
+    // a bad date is skipped rather than dropping the dates after it, and named in the error
+    pub fn parse(&mut self, content: &str) -> DynResult {
+        let json: Map<String, Value> = serde_json::from_str(content)?;
+        let mut bad = Vec::new();
+        for day in json.get("holidays").unwrap_or(&json!([])).as_array().ok_or("holidays is not a list")? {
+            match day.as_str().and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()) {
+                Some(day) => {
+                    self.holidays.insert(day);
+                }
+                None => bad.push(day.to_string()),
+            }
+        }
+        if !bad.is_empty() {
+            return Err(format!("bad holidays {}", bad.join(", ")).into());
+        }
+        Ok(())
+    }
+
+    pub fn is_trading_day(&self, day: NaiveDate) -> bool {
+        !matches!(day.weekday(), Weekday::Sat | Weekday::Sun) && !self.holidays.contains(&day)
+    }
+
+    // phase of the session at the given time
+    pub fn phase(&self, now: DateTime<FixedOffset>) -> Phase {
+        let now = now.with_timezone(&exchange_tz());
+        if !self.is_trading_day(now.date_naive()) {
+            return Phase::Closed;
+        }
+        let time = now.time();
+        if time < hm(9, 15) || time >= hm(15, 0) { Phase::Closed }
+        else if time < hm(9, 30) { Phase::PreOpen }
+        else if time < hm(11, 30) { Phase::Morning }
+        else if time < hm(13, 0) { Phase::Lunch }
+        else { Phase::Afternoon }
+    }
+
+    // time when the next trading phase starts, either after lunch or on the next trading day
+    pub fn next_open(&self, now: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let now = now.with_timezone(&exchange_tz());
+        let tz = exchange_tz();
+        let at = |day: NaiveDate, time: NaiveTime| tz.from_local_datetime(&day.and_time(time)).unwrap();
+        let today = now.date_naive();
+        if self.is_trading_day(today) {
+            if now.time() < hm(9, 15) {
+                return at(today, hm(9, 15));
+            }
+            if self.phase(now) == Phase::Lunch {
+                return at(today, hm(13, 0));
+            }
+        }
+        // look for the next trading day, a year of holidays at most
+        let mut day = today + Duration::days(1);
+        for _ in 0..366 {
+            if self.is_trading_day(day) {
+                break;
+            }
+            day += Duration::days(1);
+        }
+        at(day, hm(9, 15))
+    }
+
+    // time left until the next open as text like "1d 02:03:04"
+    pub fn countdown(&self, now: DateTime<FixedOffset>) -> String {
+        let left = (self.next_open(now) - now).num_seconds().max(0);
+        let (days, hours, mins, secs) = (left / 86400, left % 86400 / 3600, left % 3600 / 60, left % 60);
+        if days > 0 {
+            format!("{}d {:02}:{:02}:{:02}", days, hours, mins, secs)
+        } else {
+            format!("{:02}:{:02}:{:02}", hours, mins, secs)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(day: u32, hour: u32, min: u32) -> DateTime<FixedOffset> {
+        exchange_tz().with_ymd_and_hms(2024, 10, day, hour, min, 0).unwrap()
+    }
+
+    // the national day week of 2024, tuesday the 1st to monday the 7th
+    fn calendar() -> Calendar {
+        let mut calendar = Calendar::default();
+        calendar.parse(r#"{"holidays":["2024-10-01","2024-10-02","2024-10-03","2024-10-04","2024-10-07"]}"#).unwrap();
+        calendar
+    }
+
+    #[test]
+    fn phases_of_a_trading_day() {
+        let calendar = calendar();
+        // tuesday the 8th is the first trading day after the holidays
+        let phases: Vec<Phase> = [(9, 0), (9, 15), (9, 29), (9, 30), (11, 29), (11, 30), (12, 59), (13, 0), (14, 59), (15, 0)]
+            .iter().map(|(hour, min)| calendar.phase(at(8, *hour, *min))).collect();
+        assert_eq!(phases, [Phase::Closed, Phase::PreOpen, Phase::PreOpen, Phase::Morning, Phase::Morning,
+            Phase::Lunch, Phase::Lunch, Phase::Afternoon, Phase::Afternoon, Phase::Closed]);
+        // holidays and weekends are closed all day
+        assert_eq!(calendar.phase(at(7, 10, 0)), Phase::Closed);
+        assert_eq!(calendar.phase(at(12, 10, 0)), Phase::Closed);
+        // the time is taken on the exchange clock, 02:00 UTC is 10:00 in Shanghai
+        assert_eq!(calendar.phase(Utc.with_ymd_and_hms(2024, 10, 8, 2, 0, 0).unwrap().fixed_offset()), Phase::Morning);
+    }
+
+    #[test]
+    fn next_open_and_countdown() {
+        let calendar = calendar();
+        // before the open
+        assert_eq!(calendar.next_open(at(8, 8, 0)), at(8, 9, 15));
+        assert_eq!(calendar.countdown(at(8, 8, 0)), "01:15:00");
+        // at lunch, the afternoon session
+        assert_eq!(calendar.next_open(at(8, 12, 0)), at(8, 13, 0));
+        assert_eq!(calendar.countdown(at(8, 12, 0)), "01:00:00");
+        // after the close on a friday, the monday
+        assert_eq!(calendar.next_open(at(11, 15, 0)), at(14, 9, 15));
+        assert_eq!(calendar.countdown(at(11, 15, 0)), "2d 18:15:00");
+        // on a weekend
+        assert_eq!(calendar.next_open(at(13, 10, 0)), at(14, 9, 15));
+        // before the holidays, over them and the weekend in them
+        let before = exchange_tz().with_ymd_and_hms(2024, 9, 30, 15, 30, 0).unwrap();
+        assert_eq!(calendar.next_open(before), at(8, 9, 15));
+        assert_eq!(calendar.countdown(before), "7d 17:45:00");
+    }
+
+    #[test]
+    fn bad_holidays_are_reported_without_dropping_the_others() {
+        let mut calendar = Calendar::default();
+        let err = calendar.parse(r#"{"holidays":["2024-10-01","10/02/2024",3,"2024-10-03"]}"#).unwrap_err();
+        assert_eq!(err.to_string(), r#"bad holidays "10/02/2024", 3"#);
+        assert_eq!(calendar.holidays.len(), 2);
+        assert!(!calendar.is_trading_day(NaiveDate::from_ymd_opt(2024, 10, 3).unwrap()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("holidays.json");
+        let (calendar, err) = Calendar::load(&path);
+        assert!(calendar.holidays.is_empty() && err.is_none());
+        fs::write(&path, r#"{"holidays":["2024-10-01",}"#).unwrap();
+        let (calendar, err) = Calendar::load(&path);
+        assert!(calendar.holidays.is_empty());
+        assert!(err.unwrap().contains("holidays.json"));
+        assert!(Calendar::load(dir.path()).1.is_some());
+    }
+}
@@ -0,0 +1,161 @@
+/*
+Command line interface, the TUI runs when no command is given.
+    stock import <file> [--format csv|json|broker] [--map code=<column>,...] [--dry-run]
+    stock export <file> [--format csv|json|broker]
+    stock serve [--port <port>] [--demo]
+    stock status-line [--codes <code>,..] [--format <template>] [--tmux] [--max-age <secs>]
+The TUI modes record, replay and demo are run by the binary, see replay and demo.
+*/
+use std::fs;
+
+use crate::{Config, DynResult, cache, store, provider, status::{self, Template}, transfer::{self, Format, Mapping}};
+
+pub const USAGE: &str = "usage:
+    stock                   run the TUI, which takes json commands on ~/.stocks.sock (see control)
+    stock record <file>     run the TUI, recording the session into the file
+    stock replay <file> [--speed <n>]
+                            replay a recorded session n times as fast (1 by default), then go on interactively
+    stock demo [--speed <n>]
+                            run the TUI on synthetic quotes, n times as fast as a trading day (60 by default)
+    stock import <file> [--format csv|json|broker] [--map code=<column>,name=..,quantity=..,cost=..] [--dry-run]
+    stock export <file> [--format csv|json|broker]
+    stock serve [--port <port>] [--demo]
+                            refresh without the TUI and serve quotes and the watchlist as JSON on localhost (port 8080),
+                            the quotes are synthetic with --demo
+    stock status-line [--codes <code>,..] [--format <template>] [--tmux] [--max-age <secs>]
+                            print the quotes of the codes (the watchlist by default) on one line, see status";
+
+// value of an option like "--format json", None when absent
+pub fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1)).map(|v| v.as_str())
+}
+
+// options followed by a value
+const VALUE_OPTIONS: [&str; 6] = ["--format", "--map", "--port", "--codes", "--max-age", "--speed"];
+
+// the first argument that is neither an option nor the value of one, like the file of "import --dry-run f.csv"
+pub fn positional(args: &[String]) -> Option<&str> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Some(arg);
+        }
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        }
+    }
+    None
+}
+
+// run the command in args (without the program name), None when there is no command
+pub fn run(args: &[String]) -> Option<DynResult> {
+    let command = args.first()?;
+    Some(match command.as_str() {
+        // TUI modes, run by the binary
+        "record" | "replay" | "demo" => return None,
+        "import" => import(&args[1..]),
+        "export" => export(&args[1..]),
+        "serve" => serve(&args[1..]),
+        "status-line" => status_line(&args[1..]),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("unknown command {}\n{}", command, USAGE).into()),
+    })
+}
+
+fn import(args: &[String]) -> DynResult {
+    let path = positional(args).ok_or(USAGE)?;
+    let content = String::from_utf8_lossy(&fs::read(path)?).to_string();
+    let mapping = Mapping::parse(option(args, "--map").unwrap_or(""))?;
+    let format = match option(args, "--format") {
+        Some(format) => format.parse()?,
+        None => Format::guess(path, &content, &mapping),
+    };
+    let preview = transfer::parse(&content, format, &mapping)?;
+    for row in preview.rows.iter() {
+        println!("{}", row);
+    }
+    println!("{:?}: {}", format, preview.summary());
+    if args.iter().any(|arg| arg == "--dry-run") {
+        println!("dry run, nothing imported");
+        return Ok(());
+    }
+    let db = Config::home().db_path;
+    transfer::import_into(&db, &preview.entries())?;
+    println!("imported {} stocks into {}", preview.entries().len(), db.display());
+    Ok(())
+}
+
+fn export(args: &[String]) -> DynResult {
+    let path = positional(args).ok_or(USAGE)?;
+    let format = match option(args, "--format") {
+        Some(format) => format.parse()?,
+        None => Format::guess(path, "", &Mapping::default()),
+    };
+    let config = Config::home();
+    let entries = {
+        let _lock = store::lock(&config.db_path, false)?;
+        store::load(&config.db_path)?
+    };
+    // names are only known from the last quotes
+    let quotes = cache::load(&config.cache_path);
+    let content = transfer::export(&entries, format, |code| quotes.get(code).map(|q| q.stock.title.clone()).unwrap_or_default())?;
+    fs::write(path, content)?;
+    println!("exported {} stocks to {}", entries.len(), path);
+    Ok(())
+}
+
+fn status_line(args: &[String]) -> DynResult {
+    let config = Config::home();
+    let codes = match option(args, "--codes") {
+        Some(codes) => codes.split(',').filter(|c| !c.is_empty()).map(transfer::normalize_code).collect::<Result<Vec<String>, String>>()?,
+        None => {
+            let _lock = store::lock(&config.db_path, false)?;
+            store::load(&config.db_path)?.into_iter().map(|entry| entry.code).collect()
+        }
+    };
+    let template: Template = option(args, "--format").unwrap_or(status::DEFAULT_FORMAT).parse()?;
+    let max_age = option(args, "--max-age").map(|age| age.parse()).transpose()?.unwrap_or(status::MAX_AGE_SECS);
+    let tmux = args.iter().any(|arg| arg == "--tmux");
+    println!("{}", status::status_line(&config, &codes, &template, tmux, max_age, provider::from_env()?.as_ref())?);
+    Ok(())
+}
+
+#[cfg(feature = "serve")]
+fn serve(args: &[String]) -> DynResult {
+    let port = option(args, "--port").map(|port| port.parse()).transpose()?.unwrap_or(crate::server::PORT);
+    // the scratch dir of the demo, removed when the server stops
+    let (app, _scratch) = if args.iter().any(|arg| arg == "--demo") {
+        let (app, scratch) = crate::demo::app(crate::demo::DEMO_SPEED)?;
+        (app, Some(scratch))
+    } else {
+        (crate::App::try_new()?, None)
+    };
+    crate::server::serve(app, port)
+}
+
+#[cfg(not(feature = "serve"))]
+fn serve(_args: &[String]) -> DynResult {
+    Err("this build has no serve feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn the_file_is_the_first_argument_that_is_not_an_option() {
+        assert_eq!(positional(&args("f.csv --dry-run")), Some("f.csv"));
+        assert_eq!(positional(&args("--dry-run f.csv")), Some("f.csv"));
+        assert_eq!(positional(&args("--format broker --map code=No f.csv")), Some("f.csv"));
+        assert_eq!(positional(&args("--format csv")), None);
+        assert_eq!(option(&args("f.csv --format json"), "--format"), Some("json"));
+        assert_eq!(option(&args("f.csv --format"), "--format"), None);
+    }
+}
@@ -0,0 +1,132 @@
+/*
+Control socket of a running TUI, for shell scripts and editor plugins.
+The TUI listens on the unix socket ~/.stocks.sock, each line sent is a json command and gets a json line back:
+    {"cmd":"add","code":"600000.SH"}        add a code (any format taken by import)
+    {"cmd":"remove","code":"sh600000"}      remove a code (in any format too)
+    {"cmd":"select","code":"0600000"}       select a code in the panel (in any format too)
+    {"cmd":"watchlist","path":"work.json"}  switch to another data file
+    {"cmd":"refresh"}                       refresh now
+    {"cmd":"quotes"}                        the current quotes
+Replies are {"ok":true,..} or {"ok":false,"error":..}, e.g.
+    echo '{"cmd":"quotes"}' | nc -U ~/.stocks.sock
+The commands are run by the main loop with Control::run, through the same handlers as the keys.
+*/
+use std::{fs, io::{self, BufRead, BufReader, Write}, os::unix::net::{UnixListener, UnixStream}, path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender}, thread};
+
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+
+pub const SOCKET_PATH: &str=".stocks.sock";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum Command {
+    Add { code: String },
+    Remove { code: String },
+    Select { code: String },
+    Watchlist { path: String },
+    Refresh,
+    Quotes,
+}
+
+// a command waiting for the main loop, which sends the reply back to the connection
+pub struct Request {
+    // the command, or why the line is not one
+    pub command: Result<Command, String>,
+    reply: Sender<Value>,
+}
+
+impl Request {
+    pub fn reply(self, reply: Value) {
+        // the client may be gone already, nothing to do then
+        self.reply.send(reply).unwrap_or_default();
+    }
+}
+
+// reply to a successful command, with the given fields
+pub fn ok(fields: Value) -> Value {
+    let mut reply = json!({"ok": true});
+    if let (Some(reply), Value::Object(fields)) = (reply.as_object_mut(), fields) {
+        reply.extend(fields);
+    }
+    reply
+}
+
+pub fn error(message: &str) -> Value {
+    json!({"ok": false, "error": message})
+}
+
+pub fn socket_path() -> PathBuf {
+    dirs_next::home_dir().unwrap().join(SOCKET_PATH)
+}
+
+// the listening socket, removed when dropped
+pub struct Control {
+    path: PathBuf,
+    pub requests: Receiver<Request>,
+}
+
+#[cfg(feature = "tui")]
+impl Control {
+    // run the commands waiting on the socket, returning how many there were
+    pub fn run(&self, app: &mut crate::App) -> usize {
+        let mut count = 0;
+        while let Ok(request) = self.requests.try_recv() {
+            let reply = match request.command.clone() {
+                Ok(command) => crate::events::on_command(command, app),
+                Err(err) => error(&err),
+            };
+            request.reply(reply);
+            count += 1;
+        }
+        count
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
+
+// listen on the socket, taking over a socket file left behind by an instance that is gone
+// fails if another running instance is listening on it
+pub fn listen(path: &Path) -> io::Result<Control> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is used by another instance", path.display())));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let (sender, requests) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let sender = sender.clone();
+            thread::spawn(move || serve(stream, sender));
+        }
+    });
+    Ok(Control { path: path.to_path_buf(), requests })
+}
+
+// pass the lines of a connection to the main loop one by one, and write back the replies
+fn serve(stream: UnixStream, sender: Sender<Request>) {
+    let Ok(mut writer) = stream.try_clone() else { return };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (reply, replies) = channel();
+        let command = serde_json::from_str(&line).map_err(|err| format!("invalid command: {}", err));
+        // the main loop is gone
+        if sender.send(Request { command, reply }).is_err() {
+            return;
+        }
+        let reply = replies.recv().unwrap_or_else(|_| error("no reply"));
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
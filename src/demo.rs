@@ -0,0 +1,305 @@
+/*
+Synthetic quotes for demos, screenshots and working on the UI outside trading hours.
+Each code gets its own random walk, seeded by the code so a code always starts from the same day:
+    yestclose       a price between 3 and 100
+    open            a gap of about 1% from yestclose
+    price           a geometric random walk from the open, with a daily volatility of about 2%, kept within the ±10% limits
+    high, low       the extremes of the prices seen
+    volume          grows with simulated time, by lots of 100 shares
+    bids, asks      5 levels each side around the price, 0.01 apart
+Simulated time runs speed times as fast as the clock (60 by default, a trading minute per second),
+and the App auto refreshes every DEMO_REFRESH_SECS at any hour.
+After the 4 hours of a trading day the walk closes the day, and the next one opens from its close.
+Simulated time goes through the sessions of the trading days from today on, skipping the lunch break, nights and weekends.
+Quotes are stamped and the App is clocked on it, so the sparkline and the history roll over with the simulated days.
+The demo runs in a scratch dir starting from the watchlist of the user, so the synthetic quotes never reach the real cache and history.
+*/
+use std::{collections::HashMap, error::Error, sync::{Arc, Mutex}, time::Instant};
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tempfile::TempDir;
+
+use crate::{App, Config, calendar::{self, Calendar, Clock}, net::{FetchError, HttpConfig}, provider::{Provider, Quote}, store::{self, Entry}};
+
+pub const DEMO: &str="demo";
+pub const DEMO_SPEED: f64=60.0;
+pub const DEMO_REFRESH_SECS: u64=2;
+// the watchlist of the demo when the user has none
+pub const DEMO_CODES: [&str; 5] = ["0600000", "0600519", "0601318", "1000001", "1000858"];
+// volatility of the log price per second of trading, about 2% over the 4 hours of a day
+const VOLATILITY: f64=0.02 / 120.0;
+// A-shares cannot move more than this from the previous close in a day
+const LIMIT: f64=0.1;
+const TICK: f64=0.01;
+// simulated seconds of trading in a day, and in its morning session
+const DAY_SECS: f64=4.0 * 3600.0;
+const MORNING_SECS: f64=2.0 * 3600.0;
+
+// the day of one code so far
+struct Walk {
+    rng: StdRng,
+    title: String,
+    yestclose: f64,
+    open: f64,
+    price: f64,
+    high: f64,
+    low: f64,
+    volume: f64,
+    // simulated seconds of trading so far today
+    secs: f64,
+    // trading days closed so far
+    days: u32,
+}
+
+// seed of a code, FNV-1a so it is the same on every build and platform
+fn seed(code: &str) -> u64 {
+    code.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// round to the tick of 0.01
+fn round(price: f64) -> f64 {
+    (price * 100.0).round() / 100.0
+}
+
+// a standard normal sample, by the Box-Muller transform
+fn normal(rng: &mut StdRng) -> f64 {
+    let u: f64 = 1.0 - rng.gen::<f64>();
+    let v: f64 = rng.gen();
+    (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+}
+
+impl Walk {
+    fn new(code: &str) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed(code));
+        let yestclose = round(rng.gen_range(3.0..100.0));
+        // the number without the market digit, like 600000
+        let title = format!("DEMO{}", code.get(1..).unwrap_or(code));
+        let mut walk = Self { rng, title, yestclose, open: 0.0, price: 0.0, high: 0.0, low: 0.0, volume: 0.0, secs: 0.0, days: 0 };
+        walk.open_day(yestclose);
+        walk
+    }
+
+    // start a day from the close of the previous one
+    fn open_day(&mut self, yestclose: f64) {
+        self.yestclose = yestclose;
+        let gap = 0.01 * normal(&mut self.rng);
+        self.open = self.limit(round(yestclose * (1.0 + gap)));
+        (self.price, self.high, self.low) = (self.open, self.open, self.open);
+        self.volume = self.rng.gen_range(100..1000) as f64 * 100.0;
+        self.secs = 0.0;
+    }
+
+    // keep the price within the limits of the day, which are rounded to the tick too
+    fn limit(&self, price: f64) -> f64 {
+        price.clamp(round(self.yestclose * (1.0 - LIMIT)), round(self.yestclose * (1.0 + LIMIT)))
+    }
+
+    // move the price up to the simulated seconds since the start of the demo
+    // a walk started late goes through the days before, so a code is at the same place whenever it is added
+    fn step(&mut self, to: f64) {
+        self.advance(to - (self.days as f64 * DAY_SECS + self.secs));
+    }
+
+    // move the price by simulated seconds, closing the days that ended on the way
+    fn advance(&mut self, mut secs: f64) {
+        while secs > 0.0 {
+            let run = secs.min(DAY_SECS - self.secs);
+            self.walk(run);
+            self.secs += run;
+            secs -= run;
+            if self.secs >= DAY_SECS {
+                self.open_day(self.price);
+                self.days += 1;
+            }
+        }
+    }
+
+    // move the price by simulated seconds within the day
+    fn walk(&mut self, secs: f64) {
+        if secs <= 0.0 {
+            return;
+        }
+        let price = self.price * (VOLATILITY * secs.sqrt() * normal(&mut self.rng)).exp();
+        self.price = self.limit(round(price));
+        self.high = self.high.max(self.price);
+        self.low = self.low.min(self.price);
+        // about a lot every simulated second, more when the price moves
+        let lots = secs * self.rng.gen_range(0.5..1.5) * (1.0 + 100.0 * ((self.price - self.open) / self.open).abs());
+        self.volume += lots.round() * 100.0;
+    }
+
+    fn quote(&mut self, start: &Start) -> Quote {
+        // the best bid is the price itself, the best ask a tick above
+        let price = self.price;
+        let rng = &mut self.rng;
+        let mut level = |offset: f64| (round(price + offset * TICK), rng.gen_range(1..50) as f64 * 100.0);
+        let bids = (0..5).map(|i| level(-(i as f64))).collect();
+        let asks = (1..=5).map(|i| level(i as f64)).collect();
+        Quote {
+            title: self.title.clone(),
+            price: self.price,
+            percent: (self.price - self.yestclose) / self.yestclose,
+            open: self.open,
+            yestclose: self.yestclose,
+            high: self.high,
+            low: self.low,
+            volume: self.volume,
+            time: Some(start.time(self.days, self.secs)),
+            bids,
+            asks,
+        }
+    }
+}
+
+// the start of the simulated time, at the morning open of the first trading day from today
+#[derive(Clone, Copy)]
+struct Start {
+    day: NaiveDate,
+    at: Instant,
+    speed: f64,
+}
+
+impl Start {
+    fn new(speed: f64) -> Self {
+        // only weekends are skipped, the demo does not know the holidays of the user
+        let calendar = Calendar::default();
+        let mut day = calendar::exchange_now().date_naive();
+        while !calendar.is_trading_day(day) {
+            day += Duration::days(1);
+        }
+        Self { day, at: Instant::now(), speed }
+    }
+
+    // simulated seconds of trading since the start
+    fn secs(&self) -> f64 {
+        self.at.elapsed().as_secs_f64() * self.speed
+    }
+
+    // time on the exchange clock after some trading days and seconds of trading into the next one
+    fn time(&self, days: u32, secs: f64) -> DateTime<FixedOffset> {
+        let calendar = Calendar::default();
+        let mut day = self.day;
+        for _ in 0..days {
+            day += Duration::days(1);
+            while !calendar.is_trading_day(day) {
+                day += Duration::days(1);
+            }
+        }
+        let (session, secs) = if secs < MORNING_SECS { ((9, 30), secs) } else { ((13, 0), secs - MORNING_SECS) };
+        let open = NaiveTime::from_hms_opt(session.0, session.1, 0).unwrap();
+        calendar::exchange_tz().from_local_datetime(&day.and_time(open)).unwrap() + Duration::seconds(secs as i64)
+    }
+
+    // the time at the simulated seconds since the start
+    fn now(&self) -> DateTime<FixedOffset> {
+        let secs = self.secs();
+        let days = (secs / DAY_SECS).floor();
+        self.time(days as u32, secs - days * DAY_SECS)
+    }
+}
+
+// generates quotes with a random walk per code, never fails
+pub struct Demo {
+    start: Start,
+    walks: Mutex<HashMap<String, Walk>>,
+}
+
+impl Demo {
+    pub fn new(speed: f64) -> Self {
+        Self { start: Start::new(speed), walks: Mutex::new(HashMap::new()) }
+    }
+
+    // the simulated time, to clock the App on
+    pub fn clock(&self) -> Clock {
+        let start = self.start;
+        Arc::new(move || start.now())
+    }
+}
+
+impl Provider for Demo {
+    fn name(&self) -> &str {
+        DEMO
+    }
+
+    fn fetch(&self, codes: &[String], _http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        let mut walks = self.walks.lock().unwrap();
+        let secs = self.start.secs();
+        Ok(codes.iter().map(|code| {
+            let walk = walks.entry(code.clone()).or_insert_with(|| Walk::new(code));
+            walk.step(secs);
+            (code.clone(), walk.quote(&self.start))
+        }).collect())
+    }
+
+    fn refresh_secs(&self) -> Option<u64> {
+        Some(DEMO_REFRESH_SECS)
+    }
+}
+
+// an App on the demo provider, in a scratch dir holding the watchlist of the user
+// the scratch dir is removed when dropped, so it has to outlive the App
+pub fn app(speed: f64) -> Result<(App, TempDir), Box<dyn Error>> {
+    if speed <= 0.0 {
+        return Err("speed must be positive".into());
+    }
+    let mut entries = store::load(&Config::home().db_path)?;
+    if entries.is_empty() {
+        entries = DEMO_CODES.iter().map(|code| Entry::new(code)).collect();
+    }
+    let scratch = tempfile::Builder::new().prefix("stock-demo-").tempdir()?;
+    let demo = Demo::new(speed);
+    let config = Config { clock: demo.clock(), ..Config::under(scratch.path()) };
+    store::save(&config.db_path, &entries)?;
+    let mut app = App::try_with(config, Arc::new(demo))?;
+    // the simulated time between two refreshes can be longer than a quote takes to go stale
+    app.stale_secs = app.stale_secs.max((DEMO_REFRESH_SECS as f64 * speed * 2.0) as i64);
+    Ok((app, scratch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_day_opens_from_the_close_of_the_last_one() {
+        let mut walk = Walk::new("0600000");
+        let (yestclose, volume) = (walk.yestclose, walk.volume);
+        walk.advance(DAY_SECS - 1.0);
+        assert_eq!(walk.yestclose, yestclose);
+        assert!(walk.volume > volume);
+        assert!(walk.low <= walk.price && walk.price <= walk.high);
+
+        // the last second of the day, then the next day opens
+        let volume = walk.volume;
+        walk.advance(1.0);
+        assert_ne!(walk.yestclose, yestclose);
+        assert!(walk.volume < volume);
+        assert_eq!((walk.price, walk.high, walk.low, walk.secs), (walk.open, walk.open, walk.open, 0.0));
+        assert!((walk.open - walk.yestclose).abs() <= walk.yestclose * LIMIT + TICK / 2.0);
+
+        // a long step goes through several days
+        walk.advance(DAY_SECS * 3.5);
+        assert_eq!(walk.secs, DAY_SECS * 0.5);
+        assert!((walk.price - walk.yestclose).abs() <= walk.yestclose * LIMIT + TICK / 2.0);
+    }
+
+    #[test]
+    fn quotes_are_stamped_in_the_sessions_of_the_simulated_days() {
+        // a Friday
+        let start = Start { day: NaiveDate::from_ymd_opt(2024, 7, 12).unwrap(), at: Instant::now(), speed: DEMO_SPEED };
+        let at = |day: u32, hour: u32, min: u32, sec: u32| calendar::exchange_tz().with_ymd_and_hms(2024, 7, day, hour, min, sec).unwrap();
+        assert_eq!(start.time(0, 0.0), at(12, 9, 30, 0));
+        assert_eq!(start.time(0, MORNING_SECS - 1.0), at(12, 11, 29, 59));
+        assert_eq!(start.time(0, MORNING_SECS), at(12, 13, 0, 0));
+        // the weekend is skipped
+        assert_eq!(start.time(1, 60.5), at(15, 9, 31, 0));
+
+        // a walk steps through the days it missed
+        let mut walk = Walk::new("0600000");
+        walk.step(DAY_SECS * 2.0 + MORNING_SECS);
+        assert_eq!((walk.days, walk.secs), (2, MORNING_SECS));
+        assert_eq!(walk.quote(&start).time, Some(at(16, 13, 0, 0)));
+    }
+}
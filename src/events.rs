@@ -2,15 +2,23 @@
 // use keyboard code and mouse events
 use crossterm::event::{KeyCode, Event, MouseEventKind};
 
-use crate::{App, AppState, Stock};
+use serde_json::{Value, json};
+
+use crate::{App, AppState, AppView, DynResult, Stock, transfer};
+#[cfg(unix)]
+use crate::control::{self, Command};
 
 // handle keyboard and mouse events
 pub fn on_events(event:Event, app:&mut App) {
     let total = app.stocks.lock().unwrap().len(); 
     // to indicate which stock is selected
-    let sel = app.stocks_state.selected().unwrap_or(0);
+    let sel = app.selected.unwrap_or(0);
     // to indicate whether some stock is selected
-    let selsome = app.stocks_state.selected().is_some() && sel < total;
+    let selsome = app.selected.is_some() && sel < total;
+    // the notice is about the last action, so it goes with the next key
+    if let Event::Key(_) = event {
+        app.notice.clear();
+    }
     match app.state {
         // Normal AppState
         AppState::Normal => {
@@ -23,7 +31,7 @@ pub fn on_events(event:Event, app:&mut App) {
                 }
                 // Use 'r' and 'R' to refresh stock panel
                 else if code == KeyCode::Char('r') || code == KeyCode::Char('R') { 
-                    app.refresh_stocks();
+                    refresh(app);
                 }
                 // Use 'n' and 'N' to add new stock
                 else if code == KeyCode::Char('n') || code == KeyCode::Char('N') {
@@ -31,67 +39,124 @@ pub fn on_events(event:Event, app:&mut App) {
                     app.state = AppState::Adding;
                     app.input = String::new();
                 }
+                // Use 'i' and 'I' to import stocks from a file
+                else if code == KeyCode::Char('i') || code == KeyCode::Char('I') {
+                    app.state = AppState::Importing;
+                    app.input = String::new();
+                }
+                // Use 'e' and 'E' to export stocks to a file
+                else if code == KeyCode::Char('e') || code == KeyCode::Char('E') {
+                    app.state = AppState::Exporting;
+                    app.input = String::new();
+                }
+                // Use 'h' and 'H' to switch between the list and the heatmap
+                else if code == KeyCode::Char('h') || code == KeyCode::Char('H') {
+                    app.view = match app.view {
+                        AppView::List => AppView::Heatmap,
+                        AppView::Heatmap => AppView::List,
+                    };
+                }
                 // if some stock is selected
                 // Use 'd' and 'D' to delete a selected stock
                 else if (code == KeyCode::Char('d') || code == KeyCode::Char('D')) && selsome {
                     // delete the selected stock
-                    app.stocks.lock().unwrap().remove(sel);
-                    app.save_stocks().unwrap();
-                    app.stocks_state.select(None);
+                    let ret = delete_stock(app, sel);
+                    report(app, ret);
                 }
                 // if some stock is selected and the selected is not at the top of the panel
                 // Use 'u' and 'U' to move the selected stock upward
                 else if (code == KeyCode::Char('u') || code == KeyCode::Char('U')) && selsome && sel > 0 {
                     // move upward
                     app.stocks.lock().unwrap().swap(sel, sel -1);
-                    app.save_stocks().unwrap();
-                    app.stocks_state.select(Some(sel - 1));
+                    app.selected = Some(sel - 1);
+                    let ret = app.save_stocks();
+                    report(app, ret);
                 }
                 // if some stock is selected and the selected is not at the bottom of the panel
                 // Use 'j' and 'J' to move the selected stock downward
                 else if (code == KeyCode::Char('j') || code == KeyCode::Char('J')) && selsome && sel < total - 1 {
                     // move downward
                     app.stocks.lock().unwrap().swap(sel, sel + 1);
-                    app.save_stocks().unwrap();
-                    app.stocks_state.select(Some(sel + 1));
+                    app.selected = Some(sel + 1);
+                    let ret = app.save_stocks();
+                    report(app, ret);
                 }
                 // if we want to move upward and there are stocks on the panel
                 // Use 'up' on the keyboard to move upward
                 else if code == KeyCode::Up && total > 0 {
                     // need to evaluate sel>0 to avoid exception
-                    app.stocks_state.select(Some(if sel > 0 {sel - 1} else {0}));
+                    app.selected = Some(if sel > 0 {sel - 1} else {0});
                 }
                 // if we want to move downward and there are stocks on the panel
                 // Use 'down' on the keyboard to move downward
                 else if code == KeyCode::Down && total > 0 {
                     // need to evaluate sel<total-1 to avoid exception
-                    app.stocks_state.select(Some(if sel < total - 1 {sel + 1} else {sel}));
+                    app.selected = Some(if sel < total - 1 {sel + 1} else {sel});
                 }
             }
             // Mouse events -----------------------------------------------------------------------------------
             else if let Event::Mouse(mouse) = event {
                 // move upward via mouse
-                if let MouseEventKind::Up(_button) = mouse.kind {
+                // rows only map to stocks in the list view
+                if let (MouseEventKind::Up(_button), AppView::List) = (mouse.kind, &app.view) {
                     let row = mouse.row as usize; 
                     // list starts from line 3
                     // thus minus 2
                     if row >= 2 && row < total + 2{
-                        app.stocks_state.select(Some(row - 2));
+                        select_stock(app, row - 2);
                     }
                 }
             }
         },
 
-        // Adding AppState
-        AppState::Adding => match event {
-            Event::Key(key) => match key.code {
+        // Previewing AppState
+        AppState::Previewing => if let Event::Key(key) = event {
+            match key.code {
+                // Use 'Enter' on the keyboard to confirm the import
+                KeyCode::Enter => {
+                    app.state = AppState::Normal;
+                    if let Err(err) = app.import_preview() {
+                        *app.error.lock().unwrap() = err.to_string();
+                    }
+                }
+                // Use 'Esc' on the keyboard to drop the import
+                KeyCode::Esc => {
+                    app.state = AppState::Normal;
+                    app.preview = None;
+                }
+                _ => {}
+            }
+        }
+
+        // Adding, Importing and Exporting AppState
+        AppState::Adding | AppState::Importing | AppState::Exporting => if let Event::Key(key) = event {
+            match key.code {
                 // Use 'Enter' on the keyboard to add a new stock via inputs
+                KeyCode::Enter if matches!(app.state, AppState::Adding) => {
+                    app.state = AppState::Normal;
+                    if !app.input.is_empty() {
+                        let code = app.input.clone();
+                        let ret = add_stock(app, &code);
+                        report(app, ret);
+                    }
+                }
+                // Use 'Enter' on the keyboard to preview the import of the file
+                KeyCode::Enter if matches!(app.state, AppState::Importing) => {
+                    let path = app.input.clone();
+                    match app.preview_import(&path) {
+                        Ok(()) => app.state = AppState::Previewing,
+                        Err(err) => {
+                            app.state = AppState::Normal;
+                            *app.error.lock().unwrap() = err.to_string();
+                        }
+                    }
+                }
+                // Use 'Enter' on the keyboard to export to the file
                 KeyCode::Enter => {
                     app.state = AppState::Normal;
-                    if app.input.len() > 0 {
-                        app.stocks.lock().unwrap().push(Stock::new(&app.input));
-                        app.refresh_stocks();
-                        app.save_stocks().unwrap();
+                    let path = app.input.clone();
+                    if let Err(err) = app.export(&path) {
+                        *app.error.lock().unwrap() = err.to_string();
                     }
                 }
                 // Use 'Esc' on the keyboard to exit from the Adding AppState and enter the Normal AppState
@@ -108,18 +173,103 @@ pub fn on_events(event:Event, app:&mut App) {
                 }
                 // any other, do nothing
                 _ => {}
-            },
-            // any other, do nothing
-            _ => {},
+            }
+        }
+    }
+}
+
+// show the error of an action in the title bar, e.g. when the data file cannot be written
+fn report(app:&App, ret:DynResult) {
+    if let Err(err) = ret {
+        *app.error.lock().unwrap() = err.to_string();
+    }
+}
+
+// handlers shared by the keys and the control socket ---------------------------------------------------
+
+// add a stock at the bottom of the panel
+// the stock stays in the panel when the data file cannot be written
+pub fn add_stock(app:&mut App, code:&str) -> DynResult {
+    app.stocks.lock().unwrap().push(Stock::new(code));
+    // the codes changed, so a refresh in flight is out of date
+    app.force_refresh();
+    app.save_stocks()
+}
+
+// delete the stock at the index, which unselects
+// the stock stays out of the panel when the data file cannot be written
+pub fn delete_stock(app:&mut App, index:usize) -> DynResult {
+    app.stocks.lock().unwrap().remove(index);
+    app.selected = None;
+    app.save_stocks()
+}
+
+pub fn select_stock(app:&mut App, index:usize) {
+    app.selected = Some(index);
+}
+
+pub fn refresh(app:&mut App) {
+    app.refresh_stocks();
+}
+
+// handle a command of the control socket, returning the reply
+#[cfg(unix)]
+pub fn on_command(command:Command, app:&mut App) -> Value {
+    let index = |app:&App, code:&str| app.stocks.lock().unwrap().iter().position(|s| s.code == code);
+    // the index and the normalized code of a code in the watchlist, in any format taken by import
+    let find = |app:&App, code:&str| -> Result<(usize, String), String> {
+        let code = transfer::normalize_code(code)?;
+        index(app, &code).map(|i| (i, code.clone())).ok_or(format!("{} is not in the watchlist", code))
+    };
+    match command {
+        Command::Add { code } => match transfer::normalize_code(&code) {
+            Err(err) => control::error(&err),
+            Ok(code) if index(app, &code).is_some() => control::error(&format!("{} is already in the watchlist", code)),
+            Ok(code) => match add_stock(app, &code) {
+                Ok(()) => control::ok(json!({"code": code})),
+                Err(err) => control::error(&err.to_string()),
+            }
+        },
+        Command::Remove { code } => match find(app, &code) {
+            Ok((i, code)) => match delete_stock(app, i) {
+                Ok(()) => control::ok(json!({"code": code})),
+                Err(err) => control::error(&err.to_string()),
+            }
+            Err(err) => control::error(&err),
+        },
+        Command::Select { code } => match find(app, &code) {
+            Ok((i, code)) => {
+                select_stock(app, i);
+                control::ok(json!({"code": code}))
+            }
+            Err(err) => control::error(&err),
+        },
+        Command::Watchlist { path } => {
+            // ~ is not expanded by json, so do it here
+            let path = match path.strip_prefix("~/") {
+                Some(rest) => dirs_next::home_dir().unwrap().join(rest),
+                None => std::path::PathBuf::from(&path),
+            };
+            match app.switch_watchlist(&path) {
+                Ok(()) => control::ok(json!({"path": path, "codes": app.stocks.lock().unwrap().len()})),
+                Err(err) => control::error(&err.to_string()),
+            }
+        }
+        Command::Refresh => {
+            refresh(app);
+            control::ok(json!({}))
+        }
+        Command::Quotes => {
+            let quotes:Vec<Value> = app.stocks.lock().unwrap().iter().map(Stock::to_json).collect();
+            control::ok(json!({"quotes": quotes}))
         }
     }
 }
 
 // handle timing event
 pub fn on_tick(app:&mut App) {
-    app.tick_count+=1;
-    // Every 1 min, refresh stocks
-    if app.tick_count % 60 == 0  {
+    // Every 1 min, refresh stocks, but only while the market is trading (the demo feed has its own pace)
+    if app.tick() {
         if  let AppState::Normal = app.state {  
             app.refresh_stocks();
         }
@@ -0,0 +1,234 @@
+/*
+Local price history accumulated from refreshes.
+Each snapshot is appended to one small csv file per code per day:
+    ~/.stocks_history/0600000/2023-07-14.csv    lines of "time,price,volume"
+After the close a day is compacted into a daily bar:
+    ~/.stocks_history/0600000/daily.csv         lines of "date,open,high,low,close,volume"
+Intraday files are kept for a few days after compaction, then removed.
+Compactions, of this instance or another one, run one at a time under a lock file in the history dir.
+*/
+use std::{fs::{self, OpenOptions}, io::Write, path::{Path, PathBuf}};
+
+use chrono::{Duration, NaiveDate, NaiveTime};
+
+use crate::{DynResult, Stock};
+
+pub const HISTORY_PATH: &str=".stocks_history";
+pub const DAILY_FILE: &str="daily.csv";
+// intraday files are removed this many days after compaction
+pub const KEEP_DAYS: i64=5;
+pub const LOCK_FILE: &str=".lock";
+
+// one snapshot of a stock
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub time: NaiveTime,
+    pub price: f64,
+    // cumulative volume of the day
+    pub volume: f64,
+}
+
+// one daily OHLCV bar
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bar {
+    pub date: NaiveDate,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+}
+
+fn day_file(dir: &Path, code: &str, day: NaiveDate) -> PathBuf {
+    dir.join(code).join(format!("{}.csv", day.format("%Y-%m-%d")))
+}
+
+// append a snapshot of every stock with a live quote to the history in dir
+// a quote with the time of the last snapshot of its day is the same one again, e.g. refreshed at lunch or after the close, so it is skipped
+pub fn record(dir: &Path, stocks: &[Stock]) -> DynResult {
+    for stock in stocks.iter().filter(|stock| !stock.cached && stock.price != 0.0) {
+        // the quote time tells which trading day the snapshot belongs to
+        if let Some(time) = stock.time {
+            let path = day_file(dir, &stock.code, time.date_naive());
+            let time = time.format("%H:%M:%S").to_string();
+            let recorded = fs::read_to_string(&path).unwrap_or_default();
+            if recorded.lines().last().and_then(|line| line.split(',').next()) == Some(time.as_str()) {
+                continue;
+            }
+            fs::create_dir_all(dir.join(&stock.code))?;
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{},{},{}", time, stock.price, stock.volume)?;
+        }
+    }
+    Ok(())
+}
+
+// snapshots of a code on a day, in the order they were recorded
+pub fn intraday(dir: &Path, code: &str, day: NaiveDate) -> Vec<Sample> {
+    fs::read_to_string(day_file(dir, code, day)).unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(',');
+            Some(Sample {
+                time: NaiveTime::parse_from_str(fields.next()?, "%H:%M:%S").ok()?,
+                price: fields.next()?.parse().ok()?,
+                volume: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+// daily bars of a code, oldest first
+pub fn daily(dir: &Path, code: &str) -> Vec<Bar> {
+    fs::read_to_string(dir.join(code).join(DAILY_FILE)).unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            if fields.len() != 6 {
+                return None;
+            }
+            Some(Bar {
+                date: NaiveDate::parse_from_str(fields[0], "%Y-%m-%d").ok()?,
+                open: fields[1].parse().ok()?,
+                high: fields[2].parse().ok()?,
+                low: fields[3].parse().ok()?,
+                close: fields[4].parse().ok()?,
+                volume: fields[5].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+// turn the snapshots of a day into a bar, None if nothing was recorded
+pub fn to_bar(day: NaiveDate, samples: &[Sample]) -> Option<Bar> {
+    let first = samples.first()?;
+    let last = samples.last()?;
+    Some(Bar {
+        date: day,
+        open: first.price,
+        high: samples.iter().map(|s| s.price).fold(f64::NEG_INFINITY, f64::max),
+        low: samples.iter().map(|s| s.price).fold(f64::INFINITY, f64::min),
+        close: last.price,
+        // the feed reports the volume so far, so the largest one is the volume of the day
+        volume: samples.iter().map(|s| s.volume).fold(0.0, f64::max),
+    })
+}
+
+// compact every recorded day up to and including `until` into daily bars
+// days already compacted are skipped, and old intraday files are removed
+pub fn compact(dir: &Path, until: NaiveDate) -> DynResult {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    // the daily bars are read under the lock, so two compactions cannot both append the same day
+    let lock = OpenOptions::new().create(true).truncate(false).write(true).open(dir.join(LOCK_FILE))?;
+    lock.lock()?;
+    let codes = fs::read_dir(dir)?;
+    for code in codes.flatten().filter(|entry| entry.path().is_dir()) {
+        let code = code.file_name().to_string_lossy().to_string();
+        let mut bars = daily(dir, &code);
+        let mut days: Vec<NaiveDate> = fs::read_dir(dir.join(&code))?
+            .flatten()
+            .filter_map(|entry| NaiveDate::parse_from_str(entry.file_name().to_string_lossy().trim_end_matches(".csv"), "%Y-%m-%d").ok())
+            .filter(|day| *day <= until)
+            .collect();
+        days.sort();
+        for day in days {
+            if !bars.iter().any(|bar| bar.date == day) {
+                if let Some(bar) = to_bar(day, &intraday(dir, &code, day)) {
+                    let mut file = OpenOptions::new().create(true).append(true).open(dir.join(&code).join(DAILY_FILE))?;
+                    writeln!(file, "{},{},{},{},{},{}", bar.date.format("%Y-%m-%d"), bar.open, bar.high, bar.low, bar.close, bar.volume)?;
+                    bars.push(bar);
+                }
+            }
+            if day <= until - Duration::days(KEEP_DAYS) {
+                fs::remove_file(day_file(dir, &code, day))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 7, d).unwrap()
+    }
+
+    fn sample(time: &str, price: f64, volume: f64) -> Sample {
+        Sample { time: NaiveTime::parse_from_str(time, "%H:%M:%S").unwrap(), price, volume }
+    }
+
+    fn write_day(dir: &Path, code: &str, d: u32, lines: &str) {
+        fs::create_dir_all(dir.join(code)).unwrap();
+        fs::write(day_file(dir, code, day(d)), lines).unwrap();
+    }
+
+    #[test]
+    fn a_bar_spans_the_samples_of_the_day() {
+        let samples = [sample("09:30:00", 7.5, 100.0), sample("10:00:00", 7.9, 300.0), sample("11:00:00", 7.2, 500.0), sample("15:00:03", 7.6, 800.0)];
+        let bar = to_bar(day(10), &samples).unwrap();
+        assert_eq!(bar, Bar { date: day(10), open: 7.5, high: 7.9, low: 7.2, close: 7.6, volume: 800.0 });
+        assert_eq!(to_bar(day(10), &samples[..1]).unwrap().high, 7.5);
+        assert_eq!(to_bar(day(10), &[]), None);
+    }
+
+    #[test]
+    fn the_same_quote_is_recorded_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stock = Stock::new("0600000");
+        (stock.price, stock.volume, stock.time) = (7.5, 100.0, crate::parse_quote_time("2024/07/10 11:30:00"));
+        record(dir.path(), std::slice::from_ref(&stock)).unwrap();
+        record(dir.path(), std::slice::from_ref(&stock)).unwrap();
+        assert_eq!(intraday(dir.path(), "0600000", day(10)), vec![sample("11:30:00", 7.5, 100.0)]);
+        // a new quote time is a new snapshot
+        stock.time = stock.time.map(|time| time + Duration::seconds(3));
+        record(dir.path(), std::slice::from_ref(&stock)).unwrap();
+        assert_eq!(intraday(dir.path(), "0600000", day(10)).len(), 2);
+    }
+
+    #[test]
+    fn compaction_appends_each_day_once_and_removes_old_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write_day(dir, "0600000", 1, "09:30:00,7.5,100\n15:00:03,7.6,800\n");
+        write_day(dir, "0600000", 9, "09:30:00,8,100\nnot a sample\n15:00:03,8.2,900\n");
+        write_day(dir, "0600000", 10, "09:30:00,8.2,100\n");
+        compact(dir, day(9)).unwrap();
+        let bars = daily(dir, "0600000");
+        assert_eq!(bars.iter().map(|bar| bar.date).collect::<Vec<_>>(), vec![day(1), day(9)]);
+        assert_eq!((bars[1].open, bars[1].close, bars[1].volume), (8.0, 8.2, 900.0));
+        // day 1 is more than KEEP_DAYS before day 9, the later days are kept
+        assert!(!day_file(dir, "0600000", day(1)).exists());
+        assert!(day_file(dir, "0600000", day(9)).exists() && day_file(dir, "0600000", day(10)).exists());
+        // compacting again, e.g. at the next start, adds only the new day
+        compact(dir, day(10)).unwrap();
+        compact(dir, day(10)).unwrap();
+        assert_eq!(daily(dir, "0600000").iter().map(|bar| bar.date).collect::<Vec<_>>(), vec![day(1), day(9), day(10)]);
+    }
+
+    #[test]
+    fn concurrent_compactions_do_not_duplicate_bars() {
+        let dir = tempfile::tempdir().unwrap();
+        for d in 1..=9 {
+            write_day(dir.path(), "0600000", d, "09:30:00,7.5,100\n");
+        }
+        let threads: Vec<_> = (0..4).map(|_| {
+            let dir = dir.path().to_path_buf();
+            std::thread::spawn(move || compact(&dir, day(9)).unwrap())
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(daily(dir.path(), "0600000").len(), 9);
+    }
+
+    #[test]
+    fn compacting_a_missing_dir_does_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        compact(&dir.path().join("missing"), day(9)).unwrap();
+        assert!(!dir.path().join("missing").exists());
+    }
+}
@@ -1,26 +1,51 @@
 /*
 Structure:
-        lib
+        lib (core: model, providers, storage)
         |
-events, widget, aio
+aio, calendar, net, metrics, provider, demo, cache, history, store, transfer, cli, status, control (unix only), server (behind the "serve" feature)
+        |
+events, widget, replay (TUI frontend, behind the "tui" feature)
         |
         main
 
 APP -> stock
 */
 
-use std::{io::Stdout, fs, collections::HashMap, sync::{Mutex, Arc}, thread};
+use std::{fs, collections::{HashMap, VecDeque}, path::{Path, PathBuf}, sync::{Mutex, Arc, PoisonError}, thread, time::Instant};
 
-use chrono::{DateTime, Local};
-use http_req::request;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone};
 use serde::{Serialize, Deserialize};
-use serde_json::{Value, Map, json};
-use tui::{backend::CrosstermBackend, widgets::ListState};
+use serde_json::{Value, json};
+
+use aio::Executor;
+use calendar::{Calendar, Clock, Phase};
+use net::{HttpConfig, Limiters, RetryPolicy};
+use provider::{Provider, Quote};
+use store::Entry;
 
 // can be visited outside this lib
+#[cfg(feature = "tui")]
 pub mod events;
+#[cfg(feature = "tui")]
 pub mod widget;
+#[cfg(feature = "tui")]
+pub mod replay;
 pub mod aio;
+pub mod calendar;
+pub mod net;
+pub mod metrics;
+pub mod provider;
+pub mod demo;
+pub mod cache;
+pub mod history;
+pub mod store;
+pub mod transfer;
+pub mod cli;
+pub mod status;
+#[cfg(unix)]
+pub mod control;
+#[cfg(feature = "serve")]
+pub mod server;
 
 // Define types for convenience
 // DynResult is a return type
@@ -28,10 +53,60 @@ pub mod aio;
 // when not ok, return a dyn std error message
 pub type DynResult = Result<(), Box<dyn std::error::Error>>;
 // return type of tui terminal
-pub type CrossTerminal = tui::Terminal<CrosstermBackend<Stdout>>;
-pub type TerminalFrame<'a> = tui::Frame<'a, CrosstermBackend<Stdout>>;
+#[cfg(feature = "tui")]
+pub type CrossTerminal = tui::Terminal<tui::backend::CrosstermBackend<std::io::Stdout>>;
+#[cfg(feature = "tui")]
+pub type TerminalFrame<'a> = tui::Frame<'a, tui::backend::CrosstermBackend<std::io::Stdout>>;
 
 pub const DB_PATH: &str=".stocks.json";
+// how many ticks (seconds) a price change stays highlighted
+pub const FLASH_TICKS: u8=3;
+// how many price samples to keep per stock for the sparkline (4 trading hours by minute)
+pub const SLICE_LEN: usize=240;
+// quotes older than this many seconds are shown as stale while trading
+pub const STALE_SECS: i64=300;
+// each provider allows a burst of this many requests, then this many requests per second
+pub const RATE_BURST: u32=5;
+pub const RATE_PER_SEC: f64=1.0;
+// at most this many codes are fetched in one request
+pub const BATCH_SIZE: usize=50;
+// the closing quotes come in shortly after the close, a day is compacted after a refresh this many seconds past it
+pub const CLOSE_GRACE_SECS: i64=60;
+
+// where the App keeps its files and where it gets the time from
+#[derive(Clone)]
+pub struct Config {
+    // the data file keeping the watchlist
+    pub db_path: PathBuf,
+    pub cache_path: PathBuf,
+    pub history_dir: PathBuf,
+    pub holidays_path: PathBuf,
+    pub clock: Clock,
+}
+
+impl Config {
+    // the files in the home dir of the user, on the system clock
+    pub fn home() -> Self {
+        Self::under(&dirs_next::home_dir().unwrap())
+    }
+
+    // the files under the dir, e.g. a scratch dir for a demo or a test, on the system clock
+    pub fn under(dir: &Path) -> Self {
+        Self {
+            db_path: dir.join(DB_PATH),
+            cache_path: dir.join(cache::CACHE_PATH),
+            history_dir: dir.join(history::HISTORY_PATH),
+            holidays_path: dir.join(calendar::HOLIDAYS_PATH),
+            clock: calendar::system_clock(),
+        }
+    }
+}
+
+// parse the quote time like "2023/07/14 15:00:03" from the feed, which is on the exchange clock
+pub fn parse_quote_time(time: &str) -> Option<DateTime<FixedOffset>> {
+    let time = NaiveDateTime::parse_from_str(time, "%Y/%m/%d %H:%M:%S").ok()?;
+    calendar::exchange_tz().from_local_datetime(&time).single()
+}
 
 // Define stock as a struct
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -44,20 +119,170 @@ pub struct Stock {
     pub yestclose: f64, // previous close price
     pub high: f64,      // current high price
     pub low: f64,       // current low price
+    #[serde(default)]
+    pub volume: f64,    // current volume of the day
+    #[serde(skip)]
+    pub prev_price: f64, // price before the latest change
+    #[serde(skip)]
+    pub flash: u8,       // remaining ticks to highlight the latest change
+    #[serde(skip)]
+    pub slice: VecDeque<f64>, // intraday price samples accumulated from refreshes
+    #[serde(skip)]
+    pub time: Option<DateTime<FixedOffset>>, // quote time on the exchange clock
+    #[serde(skip)]
+    pub cached: bool,    // the quote comes from the cache, not from a live fetch
+    #[serde(skip)]
+    pub quantity: Option<f64>, // number of shares held
+    #[serde(skip)]
+    pub cost: Option<f64>,     // average cost per share of the holding
+    #[serde(skip)]
+    pub bids: Vec<(f64, f64)>, // order book as (price, volume), best first
+    #[serde(skip)]
+    pub asks: Vec<(f64, f64)>,
 }
 
 impl Stock {
     // constructor
-    pub fn new(code:&String) -> Self {
+    pub fn new(code:&str) -> Self {
         Self {    // unmutable
-            code: code.clone(),
-            title: code.clone(),
+            code: code.to_string(),
+            title: code.to_string(),
             price:0.0,
             percent:0.0,
             open:0.0,
             yestclose:0.0,
             high:0.0,
             low:0.0,
+            volume:0.0,
+            prev_price:0.0,
+            flash:0,
+            slice:VecDeque::new(),
+            time:None,
+            cached:false,
+            quantity:None,
+            cost:None,
+            bids:Vec::new(),
+            asks:Vec::new(),
+        }
+    }
+
+    // the stock as kept in the data file
+    pub fn entry(&self) -> Entry {
+        Entry { code: self.code.clone(), quantity: self.quantity, cost: self.cost }
+    }
+
+    // the stock as served to other programs, with the fields the data file does not keep
+    pub fn to_json(&self) -> Value {
+        let mut json = serde_json::to_value(self).unwrap_or_default();
+        json["time"] = json!(self.time.map(|time| time.to_rfc3339()));
+        json["cached"] = json!(self.cached);
+        json["quantity"] = json!(self.quantity);
+        json["cost"] = json!(self.cost);
+        json["bids"] = json!(self.bids);
+        json["asks"] = json!(self.asks);
+        json
+    }
+
+    // write a new price, remembering the old one and starting a flash if it moved
+    // the first price after start (old price is 0) is not treated as a move
+    pub fn update_price(&mut self, price:f64, flash_ticks:u8) {
+        if self.price != 0.0 && price != self.price {
+            self.prev_price = self.price;
+            self.flash = flash_ticks;
+        }
+        self.price = price;
+        // keep the latest samples for the sparkline, dropping the oldest ones
+        if price != 0.0 {
+            self.slice.push_back(price);
+            if self.slice.len() > SLICE_LEN {
+                self.slice.pop_front();
+            }
+        }
+    }
+
+    // a quote is stale when its exchange time is before the given time
+    // stocks without a quote time yet are not flagged
+    pub fn is_stale(&self, before: DateTime<FixedOffset>) -> bool {
+        self.time.is_some_and(|time| time < before)
+    }
+
+    // count down the flash, called once per tick
+    pub fn tick(&mut self) {
+        self.flash = self.flash.saturating_sub(1);
+    }
+
+    // 1 if the latest change was upward, -1 if downward, 0 if not moved yet
+    pub fn direction(&self) -> i8 {
+        if self.prev_price == 0.0 || self.price == self.prev_price { 0 }
+        else if self.price > self.prev_price { 1 }
+        else { -1 }
+    }
+}
+
+// write the quotes of a provider into the stocks with the given codes
+pub fn apply_quotes(stocks: &mut [Stock], codes: &[String], quotes: &HashMap<String, Quote>, flash_ticks: u8) {
+    for stock in stocks.iter_mut().filter(|stock| codes.contains(&stock.code)) {
+        // if the stock code is incorrect, then the feed does not return the info
+        // we use an empty quote titled by the code to avoid exception
+        let quote = quotes.get(&stock.code).cloned().unwrap_or_else(|| Quote { title: stock.code.clone(), ..Quote::default() });
+        // the sparkline is of the day, it starts over with the first quote of a new trading day
+        if let (Some(old), Some(new)) = (stock.time, quote.time) {
+            if old.date_naive() != new.date_naive() {
+                stock.slice.clear();
+            }
+        }
+        stock.title = quote.title;
+        stock.update_price(quote.price, flash_ticks);
+        stock.percent = quote.percent;
+        stock.open = quote.open;
+        stock.yestclose = quote.yestclose;
+        stock.high = quote.high;
+        stock.low = quote.low;
+        stock.volume = quote.volume;
+        stock.time = quote.time;
+        stock.bids = quote.bids;
+        stock.asks = quote.asks;
+        stock.cached = false;
+
+        // if json.contains_key(&stock.code) {
+        //     let mut writer2 = Vec::new();
+        //     request::get(format!("http://img1.money.126.net/data/hs/time/today/{}.json",stock.code), &mut writer2)?;
+        //     println!("{:?}", format!("http://img1.money.126.net/data/hs/time/today/{}.json",stock.code));  
+        //     let json2: Map<String, Value> = serde_json::from_str(&String::from_utf8_lossy(&writer2).to_string())?;
+        //     stock.slice = json2.get("data").unwrap().as_array().unwrap()
+        //         .iter().map(|item| item.as_array().unwrap().get(2).unwrap().as_f64().unwrap())
+        //         .collect();
+        // }
+    }
+}
+
+// progress of a refresh split into batches
+struct Progress {
+    started: Instant,
+    remaining: usize,
+    succeeded: usize,
+    failed: Vec<String>,
+}
+
+// ends the job of a batch, even one that panicked, so the refresh is not shown as running or retrying forever
+struct BatchDone {
+    pending: Arc<Mutex<Option<u64>>>,
+    retry: Arc<Mutex<String>>,
+    generation: u64,
+    // the last batch to finish ends the refresh, a panicking one gives it up
+    last: bool,
+}
+
+impl Drop for BatchDone {
+    fn drop(&mut self) {
+        // the panic may have poisoned the locks, their state is still the one to clear
+        self.retry.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        if self.last || thread::panicking() {
+            // unless a newer refresh took over
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            if *pending == Some(self.generation) {
+                *pending = None;
+            }
         }
     }
 }
@@ -66,126 +291,459 @@ impl Stock {
 pub enum AppState {
     Normal,
     Adding,
+    // entering the file to import
+    Importing,
+    // entering the file to export to
+    Exporting,
+    // looking at the rows of an import before confirming it
+    Previewing,
+}
+
+// Define views of the stock panel as enum types
+pub enum AppView {
+    List,
+    Heatmap,
 }
 
 // Define APP as a struct
 pub struct App {
     pub should_exit:bool,
     pub state:AppState,
+    pub view:AppView,
     pub error:Arc<Mutex<String>>,
     pub input:String,
     pub stocks:Arc<Mutex<Vec<Stock>>>,
-    // ListState records the current selected position and the rolling position in the List module of TUI
-    pub stocks_state:ListState,
-    pub last_refresh:Arc<Mutex<DateTime<Local>>>,
+    // index of the selected stock, None when nothing is selected
+    // the frontend keeps its own view state (e.g. the rolling position of the TUI list)
+    pub selected:Option<usize>,
+    // time of the last successful refresh on the exchange clock
+    pub last_refresh:Arc<Mutex<DateTime<FixedOffset>>>,
     pub tick_count:u128,
+    // number of ticks to highlight a changed price
+    pub flash_ticks:u8,
+    // trading sessions and holidays, to pause auto refresh when the market is closed
+    pub calendar:Calendar,
+    // seconds after which a quote is shown as stale
+    pub stale_secs:i64,
+    // worker pool for background work such as network fetches
+    pub executor:Executor,
+    // generation of the refresh in flight, None when idle
+    pub pending:Arc<Mutex<Option<u64>>>,
+    // generation of the latest refresh started
+    pub generation:u64,
+    // how failed fetches are retried
+    pub retry_policy:RetryPolicy,
+    // state of the retry in progress, empty when not retrying
+    pub retry:Arc<Mutex<String>>,
+    // rate limiter of each provider by name
+    pub limiters:Arc<Limiters>,
+    // number of codes per request
+    pub batch_size:usize,
+    // timeouts, User-Agent and proxies of the quote fetcher
+    pub http:HttpConfig,
+    // the feed the quotes are fetched from
+    pub provider:Arc<dyn Provider>,
+    // counts and durations of the fetches
+    pub metrics:Arc<Mutex<metrics::Metrics>>,
+    // phase of the session at the last tick, to notice the close
+    pub last_phase:Phase,
+    // today is compacted once a refresh after this time has succeeded, None when it is not waiting for one
+    pub compact_after:Option<DateTime<FixedOffset>>,
+    // the data file keeping the watchlist
+    pub db_path:PathBuf,
+    // the last quotes, loaded at start
+    pub cache_path:PathBuf,
+    // where the refreshes are recorded
+    pub history_dir:PathBuf,
+    // the time on the exchange clock, the system clock unless a test or a replay drives it
+    pub clock:Clock,
+    // entries in the data file at the last load or save, the base to merge changes of other instances
+    pub db_base:Vec<Entry>,
+    // fingerprint of the data file at the last load or save
+    pub db_fingerprint:Option<u64>,
+    // the import waiting for confirmation
+    pub preview:Option<transfer::Preview>,
+    // result of the last action, shown in the status bar until the next key
+    pub notice:String,
 }
 
 impl App {
-    // Constructor
-    pub fn new() -> Self {
+    // Constructor, failing when the data file exists but cannot be loaded, or STOCK_PROVIDERS names an unknown provider
+    pub fn try_new() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::try_with(Config::home(), provider::from_env()?)
+    }
+
+    // Constructor with the files, the clock and the quote provider to use, e.g. a temp dir and a stub in tests
+    pub fn try_with(config: Config, provider: Arc<dyn Provider>) -> Result<Self, Box<dyn std::error::Error>> {
+        let (calendar, holidays_err) = Calendar::load(&config.holidays_path);
         let mut app = Self {   // mutable
             should_exit: false,
             state: AppState::Normal,
+            view: AppView::List,
             input: String::new(),
             error: Arc::new(Mutex::new(String::new())),
             stocks: Arc::new(Mutex::new([].to_vec())),
-            // ListState:default is 'unselected' as there might be no stocks
-            stocks_state: ListState::default(),
-            last_refresh: Arc::new(Mutex::new(Local::now())),
+            // 'unselected' as there might be no stocks
+            selected: None,
+            last_refresh: Arc::new(Mutex::new((config.clock)())),
             tick_count: 0,
+            flash_ticks: FLASH_TICKS,
+            calendar,
+            stale_secs: STALE_SECS,
+            executor: Executor::new(),
+            pending: Arc::new(Mutex::new(None)),
+            generation: 0,
+            retry_policy: RetryPolicy::default(),
+            retry: Arc::new(Mutex::new(String::new())),
+            limiters: Arc::new(Limiters::new(RATE_BURST, RATE_PER_SEC)),
+            batch_size: BATCH_SIZE,
+            http: HttpConfig::from_env(),
+            provider,
+            metrics: Arc::new(Mutex::new(metrics::Metrics::default())),
+            last_phase: Phase::Closed,
+            compact_after: None,
+            db_path: config.db_path,
+            cache_path: config.cache_path,
+            history_dir: config.history_dir,
+            clock: config.clock,
+            db_base: Vec::new(),
+            db_fingerprint: None,
+            preview: None,
+            // a bad holidays file is shown until the first key, the sessions are wrong on the days it misses
+            notice: holidays_err.map(|err| format!("HOLIDAYS: {}", err)).unwrap_or_default(),
         };
+        app.last_phase = app.calendar.phase(app.now());
         // load and refresh stocks
-        app.load_stocks().unwrap_or_default();
+        app.load_stocks()?;
         app.refresh_stocks();
-        return app;
+        // compact the days recorded while the app was not running
+        // today waits for the refresh above when its session is over, so its bar has the closing quote
+        let now = app.now();
+        app.compact_history(now.date_naive() - chrono::Duration::days(1));
+        if app.calendar.is_trading_day(now.date_naive()) && app.last_phase == Phase::Closed && now.time() >= chrono::NaiveTime::from_hms_opt(15, 0, 0).unwrap() {
+            app.compact_after = Some(now);
+        }
+        Ok(app)
     }
     
     // save stocks info into a .json file
-    pub fn save_stocks(&self) -> DynResult{
-        let db=dirs_next::home_dir().unwrap().join(DB_PATH);
+    // changes written by other instances since the last sync are merged in, not overwritten
+    pub fn save_stocks(&mut self) -> DynResult{
+        let _lock = store::lock(&self.db_path, true)?;
+        let theirs = store::load(&self.db_path)?;
+        let ours: Vec<Entry> = self.stocks.lock().unwrap().iter().map(|s| s.entry()).collect();
+        let merged = store::merge(&self.db_base, &ours, &theirs);
         // store each stock as an independent struct to allow future extendability.
-        let stocks = self.stocks.lock().unwrap();
-        let lists:Vec<_> = stocks.iter().map(|s| HashMap::from([("code", &s.code)])).collect();
-        fs::write(&db, serde_json::to_string(&HashMap::from([("stocks", lists)]))?)?;
+        store::save(&self.db_path, &merged)?;
+        self.db_fingerprint = store::fingerprint(&self.db_path);
+        let added = merged.iter().any(|entry| !ours.iter().any(|e| e.code == entry.code));
+        self.db_base = merged.clone();
+        self.set_entries(merged);
+        // fetch quotes for the codes added by another instance
+        if added {
+            self.force_refresh();
+        }
         Ok(())
     }
 
     // load stocks from a .json file
+    // a missing file is an empty list, but a corrupt one is an error, so it is not overwritten by an empty list
     pub fn load_stocks(&mut self) -> DynResult{
-        // Use unwrap_or_default to avoid the exception when the file does not exist
-        let content = fs::read_to_string(dirs_next::home_dir().unwrap().join(DB_PATH)).unwrap_or_default();
-        // If we want to import stocks directly，the compatibility is bad as all keys have to be matched, as follows
-        // self.stocks = serde_json::from_str(&content).unwrap_or_default();
-        // Instead, first convert to Map to improve compatibility
-        let json: Map<String, Value> = serde_json::from_str(&content).unwrap_or_default();
-        let mut data = self.stocks.lock().unwrap();
-        data.clear();
-        // append stock in the stocks.json file
-        // standard way of handling data
-        data.append(&mut json.get("stocks").unwrap_or(&json!([])).as_array().unwrap().iter()
-            .map(|s| Stock::new(&s.as_object().unwrap().get("code").unwrap().as_str().unwrap().to_string()))
-            .collect());    
+        let lock = store::lock(&self.db_path, false)?;
+        let entries = store::load(&self.db_path)?;
+        self.db_fingerprint = store::fingerprint(&self.db_path);
+        drop(lock);
+        self.db_base = entries.clone();
+        self.set_entries(entries);
         // return ok
         Ok(())
     }
 
+    // reload the stocks when another instance wrote the data file, called every tick
+    pub fn sync_stocks(&mut self) -> DynResult{
+        if store::fingerprint(&self.db_path) == self.db_fingerprint {
+            return Ok(());
+        }
+        let before = self.db_base.clone();
+        self.load_stocks()?;
+        // fetch quotes for the codes added by the other instance
+        if self.db_base.iter().any(|entry| !before.iter().any(|e| e.code == entry.code)) {
+            self.force_refresh();
+        }
+        Ok(())
+    }
+
+    // parse the file for an import, to be confirmed with import_preview
+    // the input is the file with the options of the import command, like "holdings.csv --map code=Stock No"
+    pub fn preview_import(&mut self, input: &str) -> DynResult {
+        let target: transfer::Target = input.parse()?;
+        let content = String::from_utf8_lossy(&fs::read(&target.path)?).to_string();
+        let format = target.format.unwrap_or_else(|| transfer::Format::guess(&target.path, &content, &target.mapping));
+        self.preview = Some(transfer::parse(&content, format, &target.mapping)?);
+        Ok(())
+    }
+
+    // merge the previewed import into the stocks
+    pub fn import_preview(&mut self) -> DynResult {
+        if let Some(preview) = self.preview.take() {
+            let existing: Vec<Entry> = self.stocks.lock().unwrap().iter().map(|s| s.entry()).collect();
+            let imported = preview.entries();
+            self.set_entries(transfer::merge(&existing, &imported));
+            self.save_stocks()?;
+            self.force_refresh();
+            self.notice = format!("IMPORTED {} STOCKS", imported.len());
+        }
+        Ok(())
+    }
+
+    // export the stocks, in the format of the input like "codes.csv --format csv",
+    // by default as json for a .json file and as a broker csv otherwise
+    pub fn export(&mut self, input: &str) -> DynResult {
+        let target: transfer::Target = input.parse()?;
+        let path = target.path.as_str();
+        let format = target.format.unwrap_or(if path.to_lowercase().ends_with(".json") { transfer::Format::Json } else { transfer::Format::Broker });
+        let stocks = self.stocks.lock().unwrap().clone();
+        let entries: Vec<Entry> = stocks.iter().map(|s| s.entry()).collect();
+        let content = transfer::export(&entries, format,
+            |code| stocks.iter().find(|s| s.code == code).map(|s| s.title.clone()).unwrap_or_default())?;
+        fs::write(path, content)?;
+        self.notice = format!("EXPORTED {} STOCKS TO {}", entries.len(), path);
+        Ok(())
+    }
+
+    // add the entry to the stocks, or update the holding if the code is already there
+    // returns whether the code was added
+    pub fn add_entry(&mut self, entry: Entry) -> Result<bool, Box<dyn std::error::Error>> {
+        let existing: Vec<Entry> = self.stocks.lock().unwrap().iter().map(|s| s.entry()).collect();
+        let added = !existing.iter().any(|e| e.code == entry.code);
+        self.set_entries(transfer::merge(&existing, &[entry]));
+        // the codes changed, so a refresh in flight is out of date
+        if added {
+            self.force_refresh();
+        }
+        self.save_stocks()?;
+        Ok(added)
+    }
+
+    // remove the stock with the code, returns whether it was there
+    pub fn remove_code(&mut self, code: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let existing: Vec<Entry> = self.stocks.lock().unwrap().iter().map(|s| s.entry()).collect();
+        if !existing.iter().any(|e| e.code == code) {
+            return Ok(false);
+        }
+        self.set_entries(existing.into_iter().filter(|e| e.code != code).collect());
+        self.save_stocks()?;
+        Ok(true)
+    }
+
+    // switch to another data file, e.g. another watchlist, keeping the current one if it cannot be loaded
+    pub fn switch_watchlist(&mut self, path: &Path) -> DynResult {
+        let previous = std::mem::replace(&mut self.db_path, path.to_path_buf());
+        if let Err(err) = self.load_stocks() {
+            self.db_path = previous;
+            return Err(err);
+        }
+        self.force_refresh();
+        Ok(())
+    }
+
+    // replace the stocks by the entries, keeping the quotes of the codes already there
+    // the merge base is left alone, so the change is merged as ours by the next save
+    pub fn set_entries(&mut self, entries: Vec<Entry>) {
+        let mut data = self.stocks.lock().unwrap();
+        if data.iter().map(|s| s.entry()).eq(entries.iter().cloned()) {
+            return;
+        }
+        let selected = self.selected.and_then(|sel| data.get(sel)).map(|s| s.code.clone());
+        let mut old = std::mem::take(&mut *data);
+        let mut added = Vec::new();
+        for entry in entries.iter() {
+            let mut stock = match old.iter().position(|s| s.code == entry.code) {
+                Some(i) => old.swap_remove(i),
+                None => {
+                    added.push(data.len());
+                    Stock::new(&entry.code)
+                }
+            };
+            stock.quantity = entry.quantity;
+            stock.cost = entry.cost;
+            data.push(stock);
+        }
+        // start with the last-known quotes until the first refresh succeeds
+        if !added.is_empty() {
+            let quotes = cache::load(&self.cache_path);
+            for i in added {
+                cache::apply(&mut data[i..=i], &quotes);
+            }
+        }
+        // keep the same stock selected if it is still there
+        self.selected = selected.and_then(|code| data.iter().position(|s| s.code == code));
+    }
+
+    // start a refresh unless one is already in flight, which then covers this one
     pub fn refresh_stocks(&mut self) {
-        let stock_clone = self.stocks.clone();
-        let err_clone = self.error.clone();
-        let last_refresh_clone = self.last_refresh.clone();
-        // get codes of stocks
-        let codes = self.get_codes();
-        if codes.len() > 0 {
-            thread::spawn(move || {
-                let mut writer = Vec::new();
-                // get stock data from online API
-                let ret = request::get(format!("{}{}","http://api.money.126.net/data/feed/", codes), &mut writer);
-                let mut locked_err = err_clone.lock().unwrap();
-                if let Err(err) = ret {
-                    *locked_err = format!("{:?}", err);
+        if self.pending.lock().unwrap().is_none() {
+            self.force_refresh();
+        }
+    }
+
+    // whether a refresh is in flight
+    pub fn is_refreshing(&self) -> bool {
+        self.pending.lock().unwrap().is_some()
+    }
+
+    // start a new refresh even if one is in flight, e.g. after the codes changed
+    // only the response of the newest refresh is applied, older ones are dropped
+    // the codes are fetched in batches at the same time, a failed batch does not fail the others
+    pub fn force_refresh(&mut self) {
+        let batches = self.get_code_batches(self.batch_size);
+        if batches.is_empty() {
+            return;
+        }
+        self.generation += 1;
+        let generation = self.generation;
+        *self.pending.lock().unwrap() = Some(generation);
+        let progress = Arc::new(Mutex::new(Progress { started: Instant::now(), remaining: batches.len(), failed: Vec::new(), succeeded: 0 }));
+        let total = batches.len();
+        let provider = self.provider.clone();
+        let clock = self.clock.clone();
+        let cache_path = self.cache_path.clone();
+        let history_dir = self.history_dir.clone();
+        for (i, codes) in batches.into_iter().enumerate() {
+            let stock_clone = self.stocks.clone();
+            let pending_clone = self.pending.clone();
+            let retry_clone = self.retry.clone();
+            let err_clone = self.error.clone();
+            let last_refresh_clone = self.last_refresh.clone();
+            let progress = progress.clone();
+            let metrics = self.metrics.clone();
+            let limiters = self.limiters.clone();
+            let policy = self.retry_policy;
+            let http = self.http.clone();
+            let provider = provider.clone();
+            let clock = clock.clone();
+            let cache_path = cache_path.clone();
+            let history_dir = history_dir.clone();
+            let flash_ticks = self.flash_ticks;
+            self.executor.spawn(move || {
+                let mut done = BatchDone { pending: pending_clone.clone(), retry: retry_clone.clone(), generation, last: false };
+                // get stock data from the provider
+                let ret = provider.fetch_with_retry(&codes, &http, &policy, &limiters,
+                    &|state| *retry_clone.lock().unwrap() = state);
+                retry_clone.lock().unwrap().clear();
+                metrics.lock().unwrap().fetched(&ret);
+                // hold the lock while applying, so a newer refresh cannot start in between
+                let pending = pending_clone.lock().unwrap();
+                // a newer refresh has started, this response is out of date
+                if *pending != Some(generation) {
+                    return;
                 }
-                else {
-                    let content = String::from_utf8_lossy(&writer);
-                    if content.starts_with("_ntes_quote_callback") {
-                        let mut stocks = stock_clone.lock().unwrap();  
-                        // the data returned from the online API contains a javascript call
-                        // we use skip,take,collect to realize a substring to extract info from that js call
-                        let json: Map<String, Value> = serde_json::from_str(&content.chars().skip(21).take(content.len() - 23).collect::<String>()).unwrap();
-                        for stock in stocks.iter_mut() {
-                            // if the stock code is incorrect, then the returned json does not contain the info
-                            // we should use unwrap_or to generatey something empty to avoid exception
-                            let obj = json.get(&stock.code).unwrap_or(&json!({})).as_object().unwrap().to_owned();
-                            stock.title = obj.get("name").unwrap_or(&json!(stock.code.clone())).as_str().unwrap().to_owned();
-                            stock.price = obj.get("price").unwrap_or(&json!(0.0)).as_f64().unwrap();
-                            stock.percent = obj.get("percent").unwrap_or(&json!(0.0)).as_f64().unwrap();
-                            stock.open = obj.get("open").unwrap_or(&json!(0.0)).as_f64().unwrap();
-                            stock.yestclose = obj.get("yestclose").unwrap_or(&json!(0.0)).as_f64().unwrap();
-                            stock.high = obj.get("high").unwrap_or(&json!(0.0)).as_f64().unwrap();
-                            stock.low = obj.get("low").unwrap_or(&json!(0.0)).as_f64().unwrap();
-
-                            // if json.contains_key(&stock.code) {
-                            //     let mut writer2 = Vec::new();
-                            //     request::get(format!("http://img1.money.126.net/data/hs/time/today/{}.json",stock.code), &mut writer2)?;
-                            //     println!("{:?}", format!("http://img1.money.126.net/data/hs/time/today/{}.json",stock.code));  
-                            //     let json2: Map<String, Value> = serde_json::from_str(&String::from_utf8_lossy(&writer2).to_string())?;
-                            //     stock.slice = json2.get("data").unwrap().as_array().unwrap()
-                            //         .iter().map(|item| item.as_array().unwrap().get(2).unwrap().as_f64().unwrap())
-                            //         .collect();
-                            // }
-                        }
-                        let mut last_refresh = last_refresh_clone.lock().unwrap();
-                        *last_refresh = Local::now();
-                        *locked_err = String::new();
+                let mut progress = progress.lock().unwrap();
+                progress.remaining -= 1;
+                match ret {
+                    Err(err) => {
+                        progress.failed.push(format!("BATCH {}/{}: {}", i + 1, total, err));
                     }
-                    else {
-                        *locked_err = String::from("Server Returns Errors");
+                    Ok(quotes) => {
+                        let mut stocks = stock_clone.lock().unwrap();
+                        apply_quotes(&mut stocks, &codes, &quotes, flash_ticks);
+                        progress.succeeded += 1;
                     }
                 }
+                // the last batch to finish reports for the whole refresh
+                if progress.remaining != 0 {
+                    return;
+                }
+                // the refresh counts as in flight until its files are written, so it is ended when the job is
+                done.last = true;
+                metrics.lock().unwrap().refreshed(progress.started.elapsed(), progress.succeeded > 0);
+                *err_clone.lock().unwrap() = if progress.failed.is_empty() || total == 1 {
+                    progress.failed.join("; ")
+                } else {
+                    format!("{}/{} BATCHES FAILED: {}", progress.failed.len(), total, progress.failed.join("; "))
+                };
+                let snapshot = (progress.succeeded > 0).then(|| stock_clone.lock().unwrap().clone());
+                drop(progress);
+                drop(pending);
+                // remember the live quotes for the next start, and keep them in the history
+                // the files are written from a copy without the locks, so the UI does not wait for the disk
+                if let Some(stocks) = snapshot {
+                    let now = clock();
+                    cache::save(&cache_path, &stocks, now).unwrap_or_default();
+                    history::record(&history_dir, &stocks).unwrap_or_default();
+                    // set once recorded, so a compaction waiting for this refresh finds its quotes
+                    *last_refresh_clone.lock().unwrap() = now;
+                }
             });
         }
     }
 
+    // advance one tick (second), shared by the TUI and the headless server
+    // returns whether the auto refresh is due, every 1 min while the market is trading unless the provider says otherwise
+    pub fn tick(&mut self) -> bool {
+        self.tick_count+=1;
+        // pick up the changes of other instances
+        if let Err(err) = self.sync_stocks() {
+            *self.error.lock().unwrap() = err.to_string();
+        }
+        // fade out the price change highlights
+        for stock in self.stocks.lock().unwrap().iter_mut() {
+            stock.tick();
+        }
+        let now = self.now();
+        let phase = self.calendar.phase(now);
+        // at the close, wait for a refresh with the closing quotes before compacting today
+        if phase != self.last_phase {
+            self.last_phase = phase;
+            if phase == Phase::Closed {
+                self.compact_after = Some(now + chrono::Duration::seconds(CLOSE_GRACE_SECS));
+            }
+        }
+        if let Some(after) = self.compact_after {
+            if *self.last_refresh.lock().unwrap() > after {
+                self.compact_after = None;
+                self.compact_history(after.date_naive());
+            }
+        }
+        // refreshing goes on after the close until the closing quotes are in
+        let closing = self.compact_after.is_some_and(|after| now >= after);
+        match self.provider.refresh_secs() {
+            Some(secs) => self.tick_count.is_multiple_of(secs.max(1) as u128),
+            None => self.tick_count.is_multiple_of(60) && (phase.is_trading() || closing),
+        }
+    }
+
+    // compact the recorded history up to and including the day into daily bars, in the background
+    pub fn compact_history(&self, until: NaiveDate) {
+        let dir = self.history_dir.clone();
+        self.executor.spawn(move || history::compact(&dir, until).unwrap_or_default());
+    }
+
+    // the time on the exchange clock
+    pub fn now(&self) -> DateTime<FixedOffset> {
+        (self.clock)()
+    }
+
+    // quotes before this time are stale, None when the market is not trading
+    // after the close the last quote of the day is the latest one, so it is not stale
+    pub fn stale_before(&self) -> Option<DateTime<FixedOffset>> {
+        let now = self.now();
+        if self.calendar.phase(now).is_trading() {
+            Some(now - chrono::Duration::seconds(self.stale_secs))
+        } else {
+            None
+        }
+    }
+
+    // split the stock codes into batches of at most `size` codes
+    // one request per batch keeps the URL short for large watchlists
+    pub fn get_code_batches(&self, size: usize) -> Vec<Vec<String>> {
+        self.stocks.lock().unwrap()
+            .chunks(size.max(1))
+            .map(|chunk| chunk.iter().map(|stock| stock.code.clone()).collect())
+            .collect()
+    }
+
     // get the stock code
     pub fn get_codes(&self) -> String {
         let codes:Vec<String> = self.stocks.lock().unwrap()
@@ -196,3 +754,31 @@ impl App {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote_at(price: f64, day: u32, hour: u32) -> HashMap<String, Quote> {
+        let time = calendar::exchange_tz().with_ymd_and_hms(2024, 7, day, hour, 0, 0).unwrap();
+        HashMap::from([(String::from("0600000"), Quote { price, time: Some(time), ..Quote::default() })])
+    }
+
+    #[test]
+    fn the_sparkline_keeps_the_latest_samples_of_the_day() {
+        let mut stocks = vec![Stock::new("0600000")];
+        let codes = vec![String::from("0600000")];
+        for i in 0..SLICE_LEN + 10 {
+            apply_quotes(&mut stocks, &codes, &quote_at(1.0 + i as f64, 10, 10), FLASH_TICKS);
+        }
+        assert_eq!(stocks[0].slice.len(), SLICE_LEN);
+        assert_eq!((stocks[0].slice[0], stocks[0].slice[SLICE_LEN - 1]), (11.0, (SLICE_LEN + 10) as f64));
+        // a missing quote is not a sample
+        apply_quotes(&mut stocks, &codes, &HashMap::new(), FLASH_TICKS);
+        assert_eq!(stocks[0].slice.len(), SLICE_LEN);
+        // the first quote of the next day starts a new line
+        apply_quotes(&mut stocks, &codes, &quote_at(7.5, 10, 15), FLASH_TICKS);
+        apply_quotes(&mut stocks, &codes, &quote_at(7.6, 11, 9), FLASH_TICKS);
+        assert_eq!(stocks[0].slice, [7.6]);
+    }
+}
@@ -1,22 +1,116 @@
-use std::{error::Error, time::{Instant, Duration}};
+use std::{error::Error, path::Path, sync::Arc, time::{Instant, Duration}};
 
-use stock::{DynResult, CrossTerminal, App, TerminalFrame, events, widget, AppState};
-use tui::{Terminal, backend::CrosstermBackend, widgets};
-use unicode_width::UnicodeWidthStr;
+use crossterm::event::{Event, KeyCode};
+use stock::{DynResult, CrossTerminal, App, Config, calendar, cli, demo, events, widget, provider, store,
+    replay::{self, Record, Recorder, Recording, Replay, ReplayClock, Step}};
+use tempfile::TempDir;
+use tui::{Terminal, backend::CrosstermBackend, widgets::ListState};
+
+// the main loop wakes up this often to run the commands of the control socket
+const CONTROL_POLL: Duration = Duration::from_millis(100);
+// how long quitting waits for the running fetches
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// the control socket, only on unix
+#[cfg(unix)]
+use stock::control::Control;
+#[cfg(not(unix))]
+struct Control;
+
+#[cfg(not(unix))]
+impl Control {
+    fn run(&self, _app: &mut App) -> usize {
+        0
+    }
+}
+
+// how the TUI session is run
+enum Mode {
+    Normal,
+    // record the session into a file
+    Record(Arc<Recorder>),
+    // replay the recorded steps n times as fast on the clock of the recording, then go on interactively
+    Replay(Vec<Record>, f64, Arc<ReplayClock>),
+}
 
 
 // TUI
 
 fn main() -> DynResult{
-    let mut app = App::new();
+    // run a command instead of the TUI when one is given
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(ret) = cli::run(&args) {
+        if let Err(err) = ret {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    // fail before entering the TUI, so the error is readable
+    // the scratch dir of a replay or a demo, removed on exit
+    let (mut app, mode, _scratch) = match start(&args) {
+        Ok(started) => started,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
     let mut terminal = init_terminal()?;
+    let recorder = match mode {
+        Mode::Normal => None,
+        Mode::Record(recorder) => Some(recorder),
+        Mode::Replay(records, speed, clock) => {
+            replay_loop(&mut terminal, &mut app, &records, speed, &clock)?;
+            None
+        }
+    };
+    let control = listen(&mut app);
     // main_loop contains majority of functionality
-    main_loop(&mut terminal, &mut app)?;
+    main_loop(&mut terminal, &mut app, recorder.as_deref(), control.as_ref())?;
+    drop(control);
     close_terminal(terminal)?;
+    // wait a moment for the running fetches, a hung one does not hold up the quit
+    app.executor.shutdown(SHUTDOWN_TIMEOUT);
 
     Ok(())
 }
 
+// create the App for the mode given by the args, with the scratch dir it runs in if any
+fn start(args: &[String]) -> Result<(App, Mode, Option<TempDir>), Box<dyn Error>> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("record") => {
+            let path = args.get(1).ok_or(cli::USAGE)?;
+            let recorder = Arc::new(Recorder::create(Path::new(path))?);
+            let config = Config::home();
+            recorder.record(Step::Start(store::load(&config.db_path)?));
+            recorder.record(Step::Clock((config.clock)()));
+            let app = App::try_with(config, Arc::new(Recording::new(provider::from_env()?, recorder.clone())))?;
+            Ok((app, Mode::Record(recorder), None))
+        }
+        Some("replay") => {
+            let path = args.get(1).ok_or(cli::USAGE)?;
+            let speed = cli::option(args, "--speed").map(|speed| speed.parse::<f64>()).transpose()?.unwrap_or(1.0);
+            if speed <= 0.0 {
+                return Err("speed must be positive".into());
+            }
+            let records = replay::load(Path::new(path))?;
+            // replay in a scratch dir, so the watchlist, cache and history of the user are not touched
+            let scratch = tempfile::Builder::new().prefix("stock-replay-").tempdir()?;
+            let clock = ReplayClock::new(replay::start_time(&records).unwrap_or_else(calendar::exchange_now));
+            let config = Config { clock: clock.clock(), ..Config::under(scratch.path()) };
+            store::save(&config.db_path, &replay::start_entries(&records))?;
+            let app = App::try_with(config, Arc::new(Replay::new(&records)))?;
+            Ok((app, Mode::Replay(records, speed, clock), Some(scratch)))
+        }
+        Some("demo") => {
+            let speed = cli::option(args, "--speed").map(|speed| speed.parse::<f64>()).transpose()?.unwrap_or(demo::DEMO_SPEED);
+            let (app, scratch) = demo::app(speed)?;
+            Ok((app, Mode::Normal, Some(scratch)))
+        }
+        _ => Ok((App::try_new()?, Mode::Normal, None)),
+    }
+}
+
 fn init_terminal() -> Result<CrossTerminal, Box<dyn Error>> {
     let mut stdout = std::io::stdout();
     crossterm::terminal::enable_raw_mode()?;
@@ -35,47 +129,100 @@ fn close_terminal(mut terminal: CrossTerminal) -> DynResult{
 }
 
 // main loop for most events
-fn main_loop(terminal: &mut CrossTerminal, app: &mut App) -> DynResult {
+// every event and tick is recorded when there is a recorder
+// the commands of the control socket are run between events
+// the screen is only drawn again after an event, a tick or a command changed it
+fn main_loop(terminal: &mut CrossTerminal, app: &mut App, recorder: Option<&Recorder>, control: Option<&Control>) -> DynResult {
     let mut last_tick = Instant::now();
+    // ListState records the selected position and the rolling position of the list,
+    // the rolling position only matters to the TUI, so it is kept here instead of in App
+    let mut list_state = ListState::default();
+    let mut redraw = true;
     while !app.should_exit {
-        terminal.draw(|f| {on_draw(f, app);})?;
+        if redraw {
+            terminal.draw(|f| {widget::draw(f, app, &mut list_state);})?;
+            redraw = false;
+        }
 
-        if crossterm::event::poll(Duration::from_secs(1).checked_sub(last_tick.elapsed()).unwrap_or_default())? {
-            events::on_events(crossterm::event::read()?, app);
+        let until_tick = Duration::from_secs(1).checked_sub(last_tick.elapsed()).unwrap_or_default();
+        if crossterm::event::poll(until_tick.min(CONTROL_POLL))? {
+            let event = crossterm::event::read()?;
+            if let Some(recorder) = recorder {
+                recorder.record(Step::Event(event.clone()));
+            }
+            events::on_events(event, app);
+            redraw = true;
         }
-        else {
+        else if last_tick.elapsed() >= Duration::from_secs(1) {
+            if let Some(recorder) = recorder {
+                recorder.record(Step::Tick);
+            }
             events::on_tick(app);
             last_tick = Instant::now();
+            redraw = true;
+        }
+        if let Some(control) = control {
+            redraw |= control.run(app) > 0;
         }
     }
 
     Ok(())
 }
 
-fn on_draw(frame: &mut TerminalFrame, app: &mut App) {
-    let chunks = widget::main_chunks(frame.size());
-    
-    // need to tune render_stateful_widget when rendering the list
-    // otherwise the rolling status is incorrect,
-    // the first parameter cannot be 'app',
-    // otherwise it conflicts with 'mut stock_state'
-    frame.render_stateful_widget(widget::stock_list(&app.stocks.lock().unwrap()), chunks[1], &mut app.stocks_state);
-    
-    // Since rendering stock list would change the rolling status, 
-    // if this value is needed later, has to do the list rendering
-    frame.render_widget(widget::title_bar(app, frame.size()), chunks[0]);
-    frame.render_widget(widget::stock_detail(app), chunks[2]);
-    frame.render_widget(widget::status_bar(app), chunks[3]);
-
-    if let AppState::Adding = app.state {
-        // clear before popup, otherwise the background color would also popup
-        frame.render_widget(widgets::Clear, chunks[4]);
-        frame.render_widget(widget::stock_input(app), chunks[4]);
-        
-        // display the cursor
-        // width() interface depends on an external lib
-        // can handle text width for many languages including Mandarin
-        frame.set_cursor(chunks[4].x + app.input.width() as u16 + 1, chunks[4].y + 1);
+// feed the recorded events and ticks into the App at their recorded times divided by speed
+// the clock of the App is moved to the time of each step, and runs on once the replay ends
+// Esc stops the replay, and a recorded exit is not replayed, so the session goes on from where it ended
+fn replay_loop(terminal: &mut CrossTerminal, app: &mut App, records: &[Record], speed: f64, clock: &ReplayClock) -> DynResult {
+    let ret = replay_steps(terminal, app, records, speed, clock);
+    clock.run();
+    ret
+}
+
+fn replay_steps(terminal: &mut CrossTerminal, app: &mut App, records: &[Record], speed: f64, clock: &ReplayClock) -> DynResult {
+    let start = Instant::now();
+    let mut list_state = ListState::default();
+    for record in records.iter().filter(|record| matches!(record.step, Step::Event(_) | Step::Tick)) {
+        let due = Duration::from_secs_f64(record.at as f64 / 1000.0 / speed);
+        loop {
+            terminal.draw(|f| {widget::draw(f, app, &mut list_state);})?;
+            let wait = due.saturating_sub(start.elapsed());
+            if wait.is_zero() {
+                break;
+            }
+            // redraw now and then, so refreshes show up while waiting
+            if crossterm::event::poll(wait.min(Duration::from_millis(100)))? {
+                if let Event::Key(key) = crossterm::event::read()? {
+                    if key.code == KeyCode::Esc {
+                        app.notice = String::from("REPLAY STOPPED");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        clock.set(record.at);
+        replay::apply(app, &record.step);
+        if app.should_exit {
+            app.should_exit = false;
+            break;
+        }
     }
-    
-}
\ No newline at end of file
+    app.notice = String::from("REPLAY ENDED");
+    Ok(())
+}
+
+// listen on the control socket, telling in the status bar why not if it cannot
+#[cfg(unix)]
+fn listen(app: &mut App) -> Option<Control> {
+    match stock::control::listen(&stock::control::socket_path()) {
+        Ok(control) => Some(control),
+        Err(err) => {
+            app.notice = format!("NO CONTROL SOCKET: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn listen(_app: &mut App) -> Option<Control> {
+    None
+}
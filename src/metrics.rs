@@ -0,0 +1,134 @@
+/*
+Metrics of the quotes and of the fetches, in the Prometheus text format.
+    stock_price, stock_percent, stock_volume                gauges per code
+    stock_refresh_duration_seconds                          histogram of whole refreshes, all batches included
+    stock_fetch_successes_total, stock_fetch_failures_total counters per batch fetch, failures by error kind
+    stock_last_success_timestamp_seconds                    unix time of the last refresh with a successful fetch
+*/
+use std::{collections::BTreeMap, fmt::Write, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::{Stock, net::FetchError};
+
+// upper bounds of the refresh duration buckets in seconds
+pub const BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+// name, help and value of a gauge per code
+type Gauge = (&'static str, &'static str, fn(&Stock) -> f64);
+const GAUGES: [Gauge; 3] = [
+    ("stock_price", "Latest price.", |s| s.price),
+    ("stock_percent", "Change since the previous close, 0.01 is 1%.", |s| s.percent),
+    ("stock_volume", "Volume of the day.", |s| s.volume),
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    // number of refreshes per bucket, the last one is +Inf
+    pub buckets: [u64; BUCKETS.len() + 1],
+    pub duration_sum: f64,
+    pub refreshes: u64,
+    pub successes: u64,
+    // failures by FetchError::kind
+    pub failures: BTreeMap<&'static str, u64>,
+    // unix time in seconds
+    pub last_success: Option<f64>,
+}
+
+impl Metrics {
+    // count the result of a batch fetch
+    pub fn fetched<T>(&mut self, ret: &Result<T, FetchError>) {
+        match ret {
+            Ok(_) => self.successes += 1,
+            Err(err) => *self.failures.entry(err.kind()).or_default() += 1,
+        }
+    }
+
+    // count a finished refresh, successful if any batch of it succeeded
+    pub fn refreshed(&mut self, duration: Duration, succeeded: bool) {
+        let secs = duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| secs <= *bound).unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.duration_sum += secs;
+        self.refreshes += 1;
+        if succeeded {
+            self.last_success = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64());
+        }
+    }
+}
+
+// escape a label value
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// the metrics of the stocks with a quote and of the fetches, in the Prometheus text format
+pub fn render(stocks: &[Stock], metrics: &Metrics) -> String {
+    let mut out = String::new();
+    let quoted: Vec<&Stock> = stocks.iter().filter(|stock| stock.price != 0.0).collect();
+    for (name, help, value) in GAUGES {
+        writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name).unwrap();
+        for stock in quoted.iter() {
+            writeln!(out, "{}{{code=\"{}\",title=\"{}\"}} {}", name, label(&stock.code), label(&stock.title), value(stock)).unwrap();
+        }
+    }
+
+    let name = "stock_refresh_duration_seconds";
+    writeln!(out, "# HELP {} Duration of refreshes, all batches included.\n# TYPE {} histogram", name, name).unwrap();
+    let mut cumulative = 0;
+    for (i, count) in metrics.buckets.iter().enumerate() {
+        cumulative += count;
+        let bound = BUCKETS.get(i).map(|b| b.to_string()).unwrap_or_else(|| String::from("+Inf"));
+        writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+    }
+    writeln!(out, "{}_sum {}\n{}_count {}", name, metrics.duration_sum, name, metrics.refreshes).unwrap();
+
+    writeln!(out, "# HELP stock_fetch_successes_total Successful batch fetches.\n# TYPE stock_fetch_successes_total counter").unwrap();
+    writeln!(out, "stock_fetch_successes_total {}", metrics.successes).unwrap();
+    writeln!(out, "# HELP stock_fetch_failures_total Failed batch fetches by error kind.\n# TYPE stock_fetch_failures_total counter").unwrap();
+    for kind in FetchError::KINDS {
+        writeln!(out, "stock_fetch_failures_total{{kind=\"{}\"}} {}", kind, metrics.failures.get(kind).unwrap_or(&0)).unwrap();
+    }
+
+    if let Some(last) = metrics.last_success {
+        writeln!(out, "# HELP stock_last_success_timestamp_seconds Unix time of the last successful refresh.\n# TYPE stock_last_success_timestamp_seconds gauge").unwrap();
+        writeln!(out, "stock_last_success_timestamp_seconds {}", last).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refreshes_fall_into_cumulative_buckets() {
+        let mut metrics = Metrics::default();
+        for ms in [50, 300, 300, 60_000] {
+            metrics.refreshed(Duration::from_millis(ms), ms < 60_000);
+        }
+        metrics.fetched::<()>(&Ok(()));
+        metrics.fetched::<()>(&Err(FetchError::Status(503)));
+        let text = render(&[], &metrics);
+        assert!(text.contains("stock_refresh_duration_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(text.contains("stock_refresh_duration_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(text.contains("stock_refresh_duration_seconds_bucket{le=\"0.5\"} 3\n"));
+        assert!(text.contains("stock_refresh_duration_seconds_bucket{le=\"30\"} 3\n"));
+        assert!(text.contains("stock_refresh_duration_seconds_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("stock_refresh_duration_seconds_count 4\n"));
+        assert!(text.contains("stock_fetch_successes_total 1\n"));
+        assert!(text.contains("stock_fetch_failures_total{kind=\"status\"} 1\n"));
+        assert!(text.contains("stock_fetch_failures_total{kind=\"network\"} 0\n"));
+        assert!(text.contains("stock_last_success_timestamp_seconds "));
+    }
+
+    #[test]
+    fn gauges_cover_the_quoted_stocks_with_escaped_labels() {
+        let mut quoted = Stock::new("0600000");
+        quoted.title = String::from("PU\"FA\\");
+        quoted.price = 7.5;
+        let text = render(&[quoted, Stock::new("1000001")], &Metrics::default());
+        assert!(text.contains("stock_price{code=\"0600000\",title=\"PU\\\"FA\\\\\"} 7.5\n"), "{}", text);
+        assert!(!text.contains("1000001"));
+        // no refresh has succeeded yet
+        assert!(!text.contains("stock_last_success_timestamp_seconds"));
+    }
+}
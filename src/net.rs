@@ -0,0 +1,410 @@
+/*
+Network layer for quote fetching:
+    HttpConfig holds timeouts, the User-Agent and the proxies
+    FetchError tells retryable errors (network, 5xx, 429) from permanent ones
+    RetryPolicy retries with exponential backoff and jitter
+    RateLimiter is a token bucket, one per provider
+*/
+use std::{collections::HashMap, env, fmt, io::{Read, Write}, net::TcpStream, sync::{Arc, Mutex}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use http_req::{request::{self, Request, RequestBuilder}, response::Response, tls, uri::Uri};
+
+const VERSION:&str = env!("CARGO_PKG_VERSION");
+
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    // timeout of each read, so a hung connection cannot block forever
+    pub read_timeout: Duration,
+    pub user_agent: String,
+    // proxy URLs like "http://proxy.corp:3128" for http and https requests
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    // hosts that bypass the proxy, a leading dot or a bare domain also matches subdomains, "*" matches all
+    pub no_proxy: Vec<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            user_agent: format!("stock/{}", VERSION),
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: Vec::new(),
+        }
+    }
+}
+
+impl HttpConfig {
+    // default config with proxies from HTTP_PROXY, HTTPS_PROXY and NO_PROXY (or their lowercase names)
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    // same as from_env, reading the variables through the given function
+    pub fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Self {
+        let read = |name: &str| var(name).or_else(|| var(&name.to_lowercase())).filter(|v| !v.is_empty());
+        Self {
+            http_proxy: read("HTTP_PROXY"),
+            https_proxy: read("HTTPS_PROXY"),
+            no_proxy: read("NO_PROXY").unwrap_or_default()
+                .split(',')
+                .map(|host| host.trim().to_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    // the proxy to use for the url, None to connect directly
+    pub fn proxy_for(&self, scheme: &str, host: &str) -> Option<&str> {
+        let host = host.to_lowercase();
+        let bypass = self.no_proxy.iter().any(|entry| {
+            let entry = entry.trim_start_matches('.');
+            entry == "*" || host == entry || host.ends_with(&format!(".{}", entry))
+        });
+        if bypass {
+            return None;
+        }
+        if scheme == "https" { self.https_proxy.as_deref() } else { self.http_proxy.as_deref() }
+    }
+}
+
+// Define errors of a fetch as enum types
+#[derive(Debug)]
+pub enum FetchError {
+    // connection failed, timed out, or was reset
+    Network(String),
+    // the server answered with a non-success status code
+    Status(u16),
+    // the server answered, but the content cannot be used
+    Server(String),
+}
+
+impl FetchError {
+    // names of the kinds of errors, e.g. for metrics
+    pub const KINDS: [&'static str; 3] = ["network", "status", "server"];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FetchError::Network(_) => "network",
+            FetchError::Status(_) => "status",
+            FetchError::Server(_) => "server",
+        }
+    }
+
+    // only errors that might go away by themselves are worth retrying
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Network(_) => true,
+            FetchError::Status(code) => *code >= 500 || *code == 429,
+            FetchError::Server(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Network(err) => write!(f, "Network Error: {}", err),
+            FetchError::Status(code) => write!(f, "Server Returns {}", code),
+            FetchError::Server(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    // total number of tries, including the first one
+    pub attempts: u32,
+    // delay before the first retry, doubled for each following one
+    pub base_delay: Duration,
+    // upper bound of the delay
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { attempts: 3, base_delay: Duration::from_millis(500), max_delay: Duration::from_secs(10) }
+    }
+}
+
+impl RetryPolicy {
+    // delay before the given retry (starting from 1)
+    // half of it is fixed and the other half is random, so clients do not retry in lockstep
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        backoff / 2 + backoff.mul_f64(jitter() / 2.0)
+    }
+
+    // the delay before the given retry without the jitter, base_delay doubled for each retry up to max_delay
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.base_delay.saturating_mul(1 << retry.saturating_sub(1).min(16)).min(self.max_delay)
+    }
+}
+
+// a random number in [0, 1), good enough for jitter
+fn jitter() -> f64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    // scramble the low bits with a xorshift step
+    let mut x = nanos as u64 | 1;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    (x % 1_000_000) as f64 / 1_000_000.0
+}
+
+// token bucket: holds up to `capacity` tokens and gains `rate` tokens per second
+pub struct RateLimiter {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, rate: f64) -> Self {
+        Self { capacity: capacity as f64, rate, tokens: capacity as f64, last: Instant::now() }
+    }
+
+    // take a token, returning how long to wait before the request may be sent
+    // the token is taken in advance, so callers queue up in order
+    pub fn acquire(&mut self) -> Duration {
+        self.acquire_at(Instant::now())
+    }
+
+    fn acquire_at(&mut self, now: Instant) -> Duration {
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+// the rate limiter of each provider by name, created on first use
+pub struct Limiters {
+    limiters: Mutex<HashMap<String, Arc<Mutex<RateLimiter>>>>,
+    capacity: u32,
+    rate: f64,
+}
+
+impl Limiters {
+    pub fn new(capacity: u32, rate: f64) -> Self {
+        Self { limiters: Mutex::new(HashMap::new()), capacity, rate }
+    }
+
+    pub fn get(&self, provider: &str) -> Arc<Mutex<RateLimiter>> {
+        self.limiters.lock().unwrap().entry(provider.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(RateLimiter::new(self.capacity, self.rate))))
+            .clone()
+    }
+}
+
+impl From<http_req::error::Error> for FetchError {
+    fn from(err: http_req::error::Error) -> Self {
+        FetchError::Network(format!("{:?}", err))
+    }
+}
+
+impl From<std::io::Error> for FetchError {
+    fn from(err: std::io::Error) -> Self {
+        FetchError::Network(format!("{:?}", err))
+    }
+}
+
+// a single GET, through the proxy if one is configured for the url, mapping failures to FetchError
+pub fn get(url: &str, config: &HttpConfig) -> Result<Vec<u8>, FetchError> {
+    get_with(url, &[], config)
+}
+
+// same as get, sending the extra headers, e.g. the Referer some feeds want
+pub fn get_with(url: &str, headers: &[(&str, &str)], config: &HttpConfig) -> Result<Vec<u8>, FetchError> {
+    let uri = Uri::try_from(url).map_err(|err| FetchError::Server(format!("Invalid URL {}: {:?}", url, err)))?;
+    let host = uri.host().unwrap_or("").to_string();
+    let mut writer = Vec::new();
+    let res = match config.proxy_for(uri.scheme(), &host) {
+        None => {
+            let mut request = Request::new(&uri);
+            request.connect_timeout(Some(config.connect_timeout))
+                .read_timeout(Some(config.read_timeout))
+                .header("User-Agent", &config.user_agent);
+            for (name, value) in headers {
+                request.header(name, value);
+            }
+            request.send(&mut writer)?
+        }
+        Some(proxy) => {
+            // the scheme of a proxy is often left out, like "proxy.corp:3128"
+            let proxy = if proxy.contains("://") { proxy.to_string() } else { format!("http://{}", proxy) };
+            let proxy = Uri::try_from(proxy.as_str()).map_err(|err| FetchError::Server(format!("Invalid proxy {}: {:?}", proxy, err)))?;
+            let mut stream = request::connect_timeout(proxy.host().unwrap_or(""), proxy.corr_port(), config.connect_timeout)?;
+            stream.set_read_timeout(Some(config.read_timeout))?;
+            stream.set_write_timeout(Some(config.read_timeout))?;
+            if uri.scheme() == "https" {
+                // tunnel through the proxy, then talk TLS to the target as if connected directly
+                connect_tunnel(&mut stream, &format!("{}:{}", host, uri.corr_port()), config)?;
+                let mut stream = tls::Config::default().connect(&host, stream)?;
+                let mut request = RequestBuilder::new(&uri);
+                request.header("User-Agent", &config.user_agent)
+                    .header("Connection", "Close")
+                    .timeout(Some(config.read_timeout));
+                for (name, value) in headers {
+                    request.header(name, value);
+                }
+                request.send(&mut stream, &mut writer)?
+            } else {
+                // plain http proxies take the full url in the request line
+                // HTTP/1.0 so the response is never chunked and ends when the connection closes
+                let extra: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+                write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: {}\r\n{}Connection: close\r\n\r\n",
+                    url, uri.host_header().unwrap_or_default(), config.user_agent, extra)?;
+                let mut raw = Vec::new();
+                stream.read_to_end(&mut raw)?;
+                Response::try_from(&raw, &mut writer)?
+            }
+        }
+    };
+    let code = u16::from(res.status_code());
+    if !res.status_code().is_success() {
+        return Err(FetchError::Status(code));
+    }
+    Ok(writer)
+}
+
+// ask the proxy to open a tunnel to the target "host:port"
+fn connect_tunnel(stream: &mut TcpStream, target: &str, config: &HttpConfig) -> Result<(), FetchError> {
+    write!(stream, "CONNECT {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\n\r\n", target, target, config.user_agent)?;
+    // read the head byte by byte, so nothing of the tunnel is consumed
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(FetchError::Network(String::from("Proxy closed the connection")));
+        }
+        head.push(byte[0]);
+    }
+    let res = Response::from_head(&head)?;
+    if !res.status_code().is_success() {
+        return Err(FetchError::Status(u16::from(res.status_code())));
+    }
+    Ok(())
+}
+
+// run the fetch until it succeeds, fails permanently or runs out of attempts
+// each try waits for the rate limiter, and on_retry is told "RETRY 2/3" before each retry
+pub fn with_retry<T, F, R>(policy: &RetryPolicy, limiter: &Mutex<RateLimiter>, mut fetch: F, on_retry: R) -> Result<T, FetchError>
+where
+    F: FnMut() -> Result<T, FetchError>,
+    R: Fn(String),
+{
+    let mut attempt = 1;
+    loop {
+        // release the limiter before sleeping so other fetches can take their turn
+        let wait = limiter.lock().unwrap().acquire();
+        thread::sleep(wait);
+        match fetch() {
+            Err(err) if err.is_retryable() && attempt < policy.attempts => {
+                let delay = policy.delay(attempt);
+                attempt += 1;
+                on_retry(format!("RETRY {}/{} IN {:.1}s: {}", attempt, policy.attempts, delay.as_secs_f64(), err));
+                thread::sleep(delay);
+            }
+            ret => return ret,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = RetryPolicy { attempts: 10, base_delay: Duration::from_millis(500), max_delay: Duration::from_secs(10) };
+        let backoffs: Vec<u128> = (1..=7).map(|retry| policy.backoff(retry).as_millis()).collect();
+        assert_eq!(backoffs, [500, 1000, 2000, 4000, 8000, 10000, 10000]);
+        // the shift is bounded, so a large retry count does not overflow
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(10));
+        // the jitter keeps the delay within the upper half of the backoff
+        for retry in 1..=7 {
+            let delay = policy.delay(retry);
+            assert!(policy.backoff(retry) / 2 <= delay && delay < policy.backoff(retry), "{:?}", delay);
+        }
+    }
+
+    // a policy retrying at once, with a limiter that never waits
+    fn quick(attempts: u32) -> (RetryPolicy, Mutex<RateLimiter>) {
+        (RetryPolicy { attempts, base_delay: Duration::ZERO, max_delay: Duration::ZERO }, Mutex::new(RateLimiter::new(100, 100.0)))
+    }
+
+    #[test]
+    fn retryable_errors_are_retried_until_success() {
+        let (policy, limiter) = quick(3);
+        let tries = Cell::new(0);
+        let retries = RefCell::new(Vec::new());
+        let ret = with_retry(&policy, &limiter, || {
+            tries.set(tries.get() + 1);
+            if tries.get() < 3 { Err(FetchError::Status(503)) } else { Ok(tries.get()) }
+        }, |state| retries.borrow_mut().push(state));
+        assert_eq!(ret.unwrap(), 3);
+        assert_eq!(retries.borrow().len(), 2);
+        assert!(retries.borrow()[0].starts_with("RETRY 2/3 IN 0.0s: Server Returns 503"), "{:?}", retries.borrow());
+        assert!(retries.borrow()[1].starts_with("RETRY 3/3"));
+    }
+
+    #[test]
+    fn fatal_errors_and_the_last_attempt_are_not_retried() {
+        let (policy, limiter) = quick(3);
+        let tries = Cell::new(0);
+        let ret: Result<(), _> = with_retry(&policy, &limiter, || {
+            tries.set(tries.get() + 1);
+            Err(FetchError::Server(String::from("Bad Payload")))
+        }, |_| panic!("retried a fatal error"));
+        assert!(matches!(ret, Err(FetchError::Server(_))));
+        assert_eq!(tries.get(), 1);
+
+        tries.set(0);
+        let ret: Result<(), _> = with_retry(&policy, &limiter, || {
+            tries.set(tries.get() + 1);
+            Err(FetchError::Network(String::from("reset")))
+        }, |_| {});
+        assert!(matches!(ret, Err(FetchError::Network(_))));
+        assert_eq!(tries.get(), 3);
+    }
+
+    #[test]
+    fn the_limiter_refills_at_its_rate() {
+        let mut limiter = RateLimiter::new(2, 4.0);
+        let start = limiter.last;
+        let at = |ms| start + Duration::from_millis(ms);
+        // the burst goes through at once
+        assert_eq!(limiter.acquire_at(start), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(start), Duration::ZERO);
+        // then each request waits for its token, a quarter of a second apart
+        assert_eq!(limiter.acquire_at(start), Duration::from_millis(250));
+        assert_eq!(limiter.acquire_at(start), Duration::from_millis(500));
+        // after a while the tokens are back, but never more than the capacity
+        assert_eq!(limiter.acquire_at(at(1500)), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(at(10_000)), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(at(10_000)), Duration::ZERO);
+        assert_eq!(limiter.acquire_at(at(10_000)), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn limiters_are_kept_per_provider() {
+        let limiters = Limiters::new(1, 1.0);
+        assert_eq!(limiters.get("sina").lock().unwrap().acquire(), Duration::ZERO);
+        // the bucket of another provider is still full
+        assert_eq!(limiters.get("tencent").lock().unwrap().acquire(), Duration::ZERO);
+        assert!(limiters.get("sina").lock().unwrap().acquire() > Duration::ZERO);
+    }
+}
@@ -0,0 +1,342 @@
+/*
+Quote providers fetch the quotes of a batch of codes from a feed.
+App refreshes through a Provider, so the feed can be swapped, e.g. for a stub in tests.
+    NetEase     https://api.money.126.net/data/feed/<codes>, json
+    Sina        https://hq.sinajs.cn/list=<codes>, GBK text like var hq_str_sh600000="name,open,..";
+    Tencent     https://qt.gtimg.cn/q=<codes>, GBK text like v_sh600000="1~name~600000~price~..";
+    Failover    tries the providers in turn, staying on the last one that answered,
+                and going back to the first one after FAILOVER_COOLDOWN_SECS
+By default the app fetches from NetEase, failing over to Sina then Tencent,
+the order can be set like STOCK_PROVIDERS=tencent,sina.
+Codes are in the NetEase format everywhere in the app, "0" + 6 digits for Shanghai, "1" + 6 digits for Shenzhen,
+Sina and Tencent take them with a market prefix like sh600000 and sz000001.
+*/
+use std::{collections::HashMap, env, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
+use encoding_rs::GBK;
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+
+use crate::{calendar, parse_quote_time, net::{self, FetchError, HttpConfig, Limiters, RetryPolicy}, transfer};
+
+pub const NETEASE: &str="netease";
+pub const NETEASE_URL: &str="https://api.money.126.net/data/feed/";
+pub const SINA: &str="sina";
+pub const SINA_URL: &str="https://hq.sinajs.cn/list=";
+// Sina refuses requests without a Referer from its own site
+pub const SINA_REFERER: &str="https://finance.sina.com.cn/";
+pub const TENCENT: &str="tencent";
+pub const TENCENT_URL: &str="https://qt.gtimg.cn/q=";
+// the failover order when STOCK_PROVIDERS is not set
+pub const PROVIDERS: &str="netease,sina,tencent";
+// a failover goes back to its first provider this long after leaving it
+pub const FAILOVER_COOLDOWN_SECS: u64=300;
+
+// one quote as sent by a feed, the fields a refresh writes into a Stock
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Quote {
+    pub title: String,
+    pub price: f64,
+    pub percent: f64,
+    pub open: f64,
+    pub yestclose: f64,
+    pub high: f64,
+    pub low: f64,
+    pub volume: f64,
+    // quote time on the exchange clock
+    pub time: Option<DateTime<FixedOffset>>,
+    // order book as (price, volume), best first, empty when the feed has none
+    #[serde(default)]
+    pub bids: Vec<(f64, f64)>,
+    #[serde(default)]
+    pub asks: Vec<(f64, f64)>,
+}
+
+pub trait Provider: Send + Sync {
+    // name of the provider, also the key of its rate limiter
+    fn name(&self) -> &str;
+
+    // quotes of the codes by code, codes unknown to the feed are left out
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError>;
+
+    // fetch with the retries of the policy, each try waiting for the rate limiter of the provider
+    // on_retry is told about each retry
+    fn fetch_with_retry(&self, codes: &[String], http: &HttpConfig, policy: &RetryPolicy, limiters: &Limiters, on_retry: &dyn Fn(String))
+        -> Result<HashMap<String, Quote>, FetchError> {
+        net::with_retry(policy, &limiters.get(self.name()), || self.fetch(codes, http), on_retry)
+    }
+
+    // seconds between auto refreshes at any hour, for feeds that do not follow the market like the demo
+    // None to auto refresh every minute while the market is trading
+    fn refresh_secs(&self) -> Option<u64> {
+        None
+    }
+}
+
+pub struct NetEase;
+
+impl NetEase {
+    // parse a response like _ntes_quote_callback({"0600000":{"name":..,"price":..}});
+    pub fn parse(content: &str) -> Result<HashMap<String, Quote>, FetchError> {
+        let json = content.trim()
+            .strip_prefix("_ntes_quote_callback(")
+            .and_then(|json| json.strip_suffix(");"))
+            .ok_or_else(|| FetchError::Server(String::from("Server Returns Errors")))?;
+        let json: Map<String, Value> = serde_json::from_str(json)
+            .map_err(|err| FetchError::Server(format!("Invalid Quotes: {}", err)))?;
+        let number = |obj: &Map<String, Value>, key: &str| obj.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0);
+        // levels 1 to 5 like "bid1" and "bidvol1", the empty ones are left out
+        let book = |obj: &Map<String, Value>, side: &str| (1..=5)
+            .map(|i| (number(obj, &format!("{}{}", side, i)), number(obj, &format!("{}vol{}", side, i))))
+            .filter(|(price, _)| *price != 0.0)
+            .collect();
+        Ok(json.iter()
+            .filter_map(|(code, obj)| obj.as_object().map(|obj| (code, obj)))
+            .map(|(code, obj)| (code.clone(), Quote {
+                title: obj.get("name").and_then(|v| v.as_str()).unwrap_or(code).to_string(),
+                price: number(obj, "price"),
+                percent: number(obj, "percent"),
+                open: number(obj, "open"),
+                yestclose: number(obj, "yestclose"),
+                high: number(obj, "high"),
+                low: number(obj, "low"),
+                volume: number(obj, "volume"),
+                time: obj.get("time").and_then(|t| t.as_str()).and_then(parse_quote_time),
+                bids: book(obj, "bid"),
+                asks: book(obj, "ask"),
+            }))
+            .collect())
+    }
+}
+
+impl Provider for NetEase {
+    fn name(&self) -> &str {
+        NETEASE
+    }
+
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        let url = format!("{}{}", NETEASE_URL, codes.join(","));
+        Self::parse(&String::from_utf8_lossy(&net::get(&url, http)?))
+    }
+}
+
+// the code with a market prefix, like sh600000 for 0600000
+pub fn market_code(code: &str) -> String {
+    match code.split_at_checked(1) {
+        Some(("0", number)) => format!("sh{}", number),
+        Some(("1", number)) => format!("sz{}", number),
+        _ => code.to_string(),
+    }
+}
+
+// parse a time on the exchange clock in the given format
+fn parse_time(time: &str, format: &str) -> Option<DateTime<FixedOffset>> {
+    let time = NaiveDateTime::parse_from_str(time, format).ok()?;
+    calendar::exchange_tz().from_local_datetime(&time).single()
+}
+
+// the quoted values of lines like <prefix>sh600000="..."; by app code, skipping the codes the feed does not know
+fn quoted_values<'a>(content: &'a str, prefix: &str) -> Vec<(String, &'a str)> {
+    content.lines()
+        .filter_map(|line| line.trim().strip_prefix(prefix))
+        .filter_map(|line| line.split_once("=\""))
+        .filter_map(|(code, value)| Some((transfer::normalize_code(code).ok()?, value.trim_end_matches(';').trim_end_matches('"'))))
+        .collect()
+}
+
+fn number(fields: &[&str], i: usize) -> f64 {
+    fields.get(i).and_then(|field| field.trim().parse().ok()).unwrap_or(0.0)
+}
+
+pub struct Sina;
+
+impl Sina {
+    // parse a GBK response of lines like var hq_str_sh600000="name,open,yestclose,price,high,low,bid,ask,volume,amount,
+    // bidvol1,bid1,..,bidvol5,bid5,askvol1,ask1,..,askvol5,ask5,date,time,status";
+    // volumes are in shares, unknown codes come as var hq_str_sh999999="";
+    pub fn parse(content: &[u8]) -> Result<HashMap<String, Quote>, FetchError> {
+        let (content, _, _) = GBK.decode(content);
+        if !content.lines().any(|line| line.trim().starts_with("var hq_str_")) {
+            return Err(FetchError::Server(String::from("Server Returns Errors")));
+        }
+        Ok(quoted_values(&content, "var hq_str_").into_iter()
+            .map(|(code, value)| (code, value.split(',').collect::<Vec<&str>>()))
+            .filter(|(_, fields)| fields.len() >= 32)
+            .map(|(code, fields)| {
+                let (price, yestclose) = (number(&fields, 3), number(&fields, 2));
+                let book = |start: usize| (0..5)
+                    .map(|i| (number(&fields, start + 2 * i + 1), number(&fields, start + 2 * i)))
+                    .filter(|(price, _)| *price != 0.0)
+                    .collect();
+                (code, Quote {
+                    title: fields[0].to_string(),
+                    price,
+                    percent: if yestclose != 0.0 && price != 0.0 { (price - yestclose) / yestclose } else { 0.0 },
+                    open: number(&fields, 1),
+                    yestclose,
+                    high: number(&fields, 4),
+                    low: number(&fields, 5),
+                    volume: number(&fields, 8),
+                    time: parse_time(&format!("{} {}", fields[30], fields[31]), "%Y-%m-%d %H:%M:%S"),
+                    bids: book(10),
+                    asks: book(20),
+                })
+            })
+            .collect())
+    }
+}
+
+impl Provider for Sina {
+    fn name(&self) -> &str {
+        SINA
+    }
+
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        let codes: Vec<String> = codes.iter().map(|code| market_code(code)).collect();
+        let url = format!("{}{}", SINA_URL, codes.join(","));
+        Self::parse(&net::get_with(&url, &[("Referer", SINA_REFERER)], http)?)
+    }
+}
+
+pub struct Tencent;
+
+impl Tencent {
+    // parse a GBK response of lines like v_sh600000="1~name~600000~price~yestclose~open~volume~outer~inner~
+    // bid1~bidvol1~..~bid5~bidvol5~ask1~askvol1~..~ask5~askvol5~trades~time~change~percent~high~low~..";
+    // volumes are in lots of 100 shares and percent in %, unknown codes come as v_pv_none_match="1";
+    pub fn parse(content: &[u8]) -> Result<HashMap<String, Quote>, FetchError> {
+        let (content, _, _) = GBK.decode(content);
+        if !content.lines().any(|line| line.trim().starts_with("v_")) {
+            return Err(FetchError::Server(String::from("Server Returns Errors")));
+        }
+        Ok(quoted_values(&content, "v_").into_iter()
+            .map(|(code, value)| (code, value.split('~').collect::<Vec<&str>>()))
+            .filter(|(_, fields)| fields.len() >= 35)
+            .map(|(code, fields)| {
+                let book = |start: usize| (0..5)
+                    .map(|i| (number(&fields, start + 2 * i), number(&fields, start + 2 * i + 1) * 100.0))
+                    .filter(|(price, _)| *price != 0.0)
+                    .collect();
+                (code, Quote {
+                    title: fields[1].to_string(),
+                    price: number(&fields, 3),
+                    percent: number(&fields, 32) / 100.0,
+                    open: number(&fields, 5),
+                    yestclose: number(&fields, 4),
+                    high: number(&fields, 33),
+                    low: number(&fields, 34),
+                    volume: number(&fields, 6) * 100.0,
+                    time: parse_time(fields[30], "%Y%m%d%H%M%S"),
+                    bids: book(9),
+                    asks: book(19),
+                })
+            })
+            .collect())
+    }
+}
+
+impl Provider for Tencent {
+    fn name(&self) -> &str {
+        TENCENT
+    }
+
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        let codes: Vec<String> = codes.iter().map(|code| market_code(code)).collect();
+        let url = format!("{}{}", TENCENT_URL, codes.join(","));
+        Self::parse(&net::get(&url, http)?)
+    }
+}
+
+// tries the providers in turn until one answers, starting from the last one that did
+// the first provider is preferred, it is tried first again once the cooldown has passed since leaving it
+// the error of the last provider tried is returned when all fail
+pub struct Failover {
+    providers: Vec<Arc<dyn Provider>>,
+    // index of the provider to try first, and since when
+    current: Mutex<(usize, Instant)>,
+    pub cooldown: Duration,
+}
+
+impl Failover {
+    // panics without providers
+    pub fn new(providers: Vec<Arc<dyn Provider>>) -> Self {
+        assert!(!providers.is_empty(), "failover without providers");
+        Self { providers, current: Mutex::new((0, Instant::now())), cooldown: Duration::from_secs(FAILOVER_COOLDOWN_SECS) }
+    }
+
+    // the index of the provider to try first
+    fn first(&self) -> usize {
+        let (index, since) = *self.current.lock().unwrap();
+        if since.elapsed() >= self.cooldown { 0 } else { index }
+    }
+
+    // try the providers in turn from the first one, fetch is given the provider and whether it is the last one tried
+    // not locked while fetching, so the batches of a refresh run at the same time
+    fn each<F>(&self, mut fetch: F) -> Result<HashMap<String, Quote>, FetchError>
+    where
+        F: FnMut(&dyn Provider, bool) -> Result<HashMap<String, Quote>, FetchError>,
+    {
+        let first = self.first();
+        let count = self.providers.len();
+        let mut failure = None;
+        for i in 0..count {
+            let index = (first + i) % count;
+            match fetch(self.providers[index].as_ref(), i + 1 == count) {
+                Ok(quotes) => {
+                    // the cooldown starts when another provider than the one tried first answers
+                    if index != first {
+                        *self.current.lock().unwrap() = (index, Instant::now());
+                    }
+                    return Ok(quotes);
+                }
+                Err(err) => failure = Some(err),
+            }
+        }
+        Err(failure.unwrap())
+    }
+}
+
+impl Provider for Failover {
+    // the name of the provider answering lately
+    fn name(&self) -> &str {
+        self.providers[self.current.lock().unwrap().0].name()
+    }
+
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        self.each(|provider, _| provider.fetch(codes, http))
+    }
+
+    // each provider is tried once on its own rate limiter, and only the last one tried is retried,
+    // so a feed that is down costs one try before the next feed is asked rather than all the retries
+    fn fetch_with_retry(&self, codes: &[String], http: &HttpConfig, policy: &RetryPolicy, limiters: &Limiters, on_retry: &dyn Fn(String))
+        -> Result<HashMap<String, Quote>, FetchError> {
+        let once = RetryPolicy { attempts: 1, ..*policy };
+        self.each(|provider, last| provider.fetch_with_retry(codes, http, if last { policy } else { &once }, limiters, on_retry))
+    }
+}
+
+// the provider by name
+pub fn by_name(name: &str) -> Result<Arc<dyn Provider>, String> {
+    match name.trim().to_lowercase().as_str() {
+        NETEASE => Ok(Arc::new(NetEase)),
+        SINA => Ok(Arc::new(Sina)),
+        TENCENT => Ok(Arc::new(Tencent)),
+        name => Err(format!("unknown provider {}, expected one of {}", name, PROVIDERS)),
+    }
+}
+
+// the providers named like "sina,tencent" failing over in that order
+pub fn from_names(names: &str) -> Result<Arc<dyn Provider>, String> {
+    let providers = names.split(',').filter(|name| !name.trim().is_empty()).map(by_name).collect::<Result<Vec<_>, String>>()?;
+    match providers.len() {
+        0 => Err(String::from("no provider given")),
+        1 => Ok(providers[0].clone()),
+        _ => Ok(Arc::new(Failover::new(providers))),
+    }
+}
+
+// the providers of STOCK_PROVIDERS, or the default ones
+pub fn from_env() -> Result<Arc<dyn Provider>, String> {
+    from_names(&env::var("STOCK_PROVIDERS").unwrap_or_else(|_| String::from(PROVIDERS)))
+}
@@ -0,0 +1,260 @@
+/*
+Recording and replay of TUI sessions, to reproduce UI bugs.
+A recording is a file of json lines, each a step with its time in ms since the start:
+    {"at":0,"step":{"Start":[{"code":"0600000"}]}}      the watchlist at the start
+    {"at":0,"step":{"Clock":"2023-07-14T10:00:00+08:00"}}   the exchange time at the start
+    {"at":1200,"step":{"Event":{"Key":{..}}}}           a crossterm event fed into events::on_events
+    {"at":2000,"step":"Tick"}                           a call of events::on_tick
+    {"at":2100,"step":{"Quotes":{"0600000":{..}}}}      quotes answered by the provider
+    {"at":2100,"step":{"Failed":"Network Error: .."}}   a fetch that failed
+Replaying feeds the events and ticks into a fresh App, and the recorded quotes come from a replay provider.
+The App of a replay runs on a replay clock, the recorded start time plus the time of the step replayed last,
+so the session phase and the times shown are the recorded ones rather than those of the wall clock.
+*/
+use std::{collections::HashMap, fs::{self, File}, io::{self, Write}, path::Path, sync::{Arc, Mutex}, time::Instant};
+
+use chrono::{DateTime, Duration, FixedOffset};
+use crossterm::event::Event;
+use serde::{Serialize, Deserialize};
+
+use crate::{App, calendar::Clock, events, net::{FetchError, HttpConfig, Limiters, RetryPolicy}, provider::{Provider, Quote}, store::Entry};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Step {
+    Start(Vec<Entry>),
+    Clock(DateTime<FixedOffset>),
+    Event(Event),
+    Tick,
+    Quotes(HashMap<String, Quote>),
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    // ms since the start of the recording
+    pub at: u64,
+    pub step: Step,
+}
+
+// appends the steps of a session to a file
+pub struct Recorder {
+    start: Instant,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self { start: Instant::now(), file: Mutex::new(File::create(path)?) })
+    }
+
+    // write the step, a failed write only loses that step
+    pub fn record(&self, step: Step) {
+        let record = Record { at: self.start.elapsed().as_millis() as u64, step };
+        if let Ok(line) = serde_json::to_string(&record) {
+            writeln!(self.file.lock().unwrap(), "{}", line).unwrap_or_default();
+        }
+    }
+}
+
+// a provider recording the answers of another one
+pub struct Recording {
+    inner: Arc<dyn Provider>,
+    recorder: Arc<Recorder>,
+}
+
+impl Recording {
+    pub fn new(inner: Arc<dyn Provider>, recorder: Arc<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl Recording {
+    // record the answer of the inner provider
+    fn record(&self, ret: Result<HashMap<String, Quote>, FetchError>) -> Result<HashMap<String, Quote>, FetchError> {
+        self.recorder.record(match &ret {
+            Ok(quotes) => Step::Quotes(quotes.clone()),
+            Err(err) => Step::Failed(err.to_string()),
+        });
+        ret
+    }
+}
+
+impl Provider for Recording {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        self.record(self.inner.fetch(codes, http))
+    }
+
+    // the inner provider retries its own way, e.g. a failover
+    fn fetch_with_retry(&self, codes: &[String], http: &HttpConfig, policy: &RetryPolicy, limiters: &Limiters, on_retry: &dyn Fn(String))
+        -> Result<HashMap<String, Quote>, FetchError> {
+        self.record(self.inner.fetch_with_retry(codes, http, policy, limiters, on_retry))
+    }
+
+    fn refresh_secs(&self) -> Option<u64> {
+        self.inner.refresh_secs()
+    }
+}
+
+// read a recording, skipping lines that cannot be parsed
+pub fn load(path: &Path) -> io::Result<Vec<Record>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+// the watchlist at the start of a recording
+pub fn start_entries(records: &[Record]) -> Vec<Entry> {
+    records.iter()
+        .find_map(|record| match &record.step {
+            Step::Start(entries) => Some(entries.clone()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+// the exchange time at the start of a recording
+// older recordings do not have it, the time of the first recorded quote is the closest then
+pub fn start_time(records: &[Record]) -> Option<DateTime<FixedOffset>> {
+    records.iter().find_map(|record| match &record.step {
+        Step::Clock(time) => Some(*time),
+        _ => None,
+    }).or_else(|| records.iter().find_map(|record| match &record.step {
+        Step::Quotes(quotes) => quotes.values().find_map(|quote| quote.time),
+        _ => None,
+    }))
+}
+
+// the clock of a replayed App, stopped at the time of the step replayed last
+// it runs on from there once the replay has ended, so the session goes on in real time
+pub struct ReplayClock {
+    start: DateTime<FixedOffset>,
+    // time of the step replayed last in ms since the start, and since when the clock runs on
+    at: Mutex<(u64, Option<Instant>)>,
+}
+
+impl ReplayClock {
+    pub fn new(start: DateTime<FixedOffset>) -> Arc<Self> {
+        Arc::new(Self { start, at: Mutex::new((0, None)) })
+    }
+
+    // move to the time of a step
+    pub fn set(&self, at: u64) {
+        *self.at.lock().unwrap() = (at, None);
+    }
+
+    // let the clock run from the current time
+    pub fn run(&self) {
+        self.at.lock().unwrap().1 = Some(Instant::now());
+    }
+
+    pub fn now(&self) -> DateTime<FixedOffset> {
+        let (at, running) = *self.at.lock().unwrap();
+        let elapsed = running.map(|since| since.elapsed().as_millis() as i64).unwrap_or_default();
+        self.start + Duration::milliseconds(at as i64 + elapsed)
+    }
+
+    // the clock to give the App
+    pub fn clock(self: &Arc<Self>) -> Clock {
+        let clock = self.clone();
+        Arc::new(move || clock.now())
+    }
+}
+
+// answers fetches with the recorded quotes, in the order they were recorded
+// the quotes seen so far are merged, so batches fetched in another order still find their codes
+// once the recording runs out, the last quotes are answered again
+pub struct Replay {
+    answers: Mutex<(Vec<Step>, HashMap<String, Quote>)>,
+}
+
+impl Replay {
+    pub fn new(records: &[Record]) -> Self {
+        let answers = records.iter()
+            .filter(|record| matches!(record.step, Step::Quotes(_) | Step::Failed(_)))
+            .rev()
+            .map(|record| record.step.clone())
+            .collect();
+        Self { answers: Mutex::new((answers, HashMap::new())) }
+    }
+}
+
+impl Provider for Replay {
+    fn name(&self) -> &str {
+        "replay"
+    }
+
+    fn fetch(&self, codes: &[String], _http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        let mut answers = self.answers.lock().unwrap();
+        let (remaining, seen) = &mut *answers;
+        match remaining.pop() {
+            Some(Step::Failed(err)) => return Err(FetchError::Server(err)),
+            Some(Step::Quotes(quotes)) => seen.extend(quotes),
+            _ => {}
+        }
+        Ok(codes.iter().filter_map(|code| seen.get(code).map(|quote| (code.clone(), quote.clone()))).collect())
+    }
+}
+
+// feed a recorded event or tick into the App, other steps are not driven by the UI
+pub fn apply(app: &mut App, step: &Step) {
+    match step {
+        Step::Event(event) => events::on_events(event.clone(), app),
+        Step::Tick => events::on_tick(app),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, min: u32) -> DateTime<FixedOffset> {
+        calendar::exchange_tz().with_ymd_and_hms(2024, 7, 10, hour, min, 0).unwrap()
+    }
+
+    fn quotes(code: &str, price: f64, time: Option<DateTime<FixedOffset>>) -> Step {
+        Step::Quotes(HashMap::from([(code.to_string(), Quote { price, time, ..Quote::default() })]))
+    }
+
+    #[test]
+    fn the_start_time_falls_back_to_the_first_quote() {
+        let clocked = [Record { at: 0, step: Step::Clock(at(10, 0)) }, Record { at: 10, step: quotes("0600000", 7.5, Some(at(9, 59))) }];
+        assert_eq!(start_time(&clocked), Some(at(10, 0)));
+        assert_eq!(start_time(&clocked[1..]), Some(at(9, 59)));
+        assert_eq!(start_time(&[Record { at: 0, step: Step::Tick }]), None);
+    }
+
+    #[test]
+    fn the_clock_stops_at_the_step_then_runs_on() {
+        let clock = ReplayClock::new(at(10, 0));
+        assert_eq!(clock.now(), at(10, 0));
+        clock.set(90_000);
+        assert_eq!((clock.clock())(), at(10, 1) + Duration::seconds(30));
+        clock.run();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(clock.now() > at(10, 1) + Duration::seconds(30));
+    }
+
+    #[test]
+    fn answers_come_in_the_recorded_order_and_the_last_ones_repeat() {
+        let records: Vec<Record> = [quotes("0600000", 7.5, None), Step::Tick, Step::Failed(String::from("FEED DOWN")), quotes("1000001", 11.2, None)]
+            .into_iter().map(|step| Record { at: 0, step }).collect();
+        let replay = Replay::new(&records);
+        let codes = [String::from("0600000"), String::from("1000001")];
+        let http = HttpConfig::default();
+        assert_eq!(replay.fetch(&codes, &http).unwrap().len(), 1);
+        assert!(matches!(replay.fetch(&codes, &http), Err(FetchError::Server(err)) if err == "FEED DOWN"));
+        // the quotes seen so far are merged, and kept once the recording runs out
+        for _ in 0..2 {
+            let quotes = replay.fetch(&codes, &http).unwrap();
+            assert_eq!((quotes["0600000"].price, quotes["1000001"].price), (7.5, 11.2));
+        }
+    }
+}
@@ -0,0 +1,320 @@
+/*
+Headless mode: the App refreshes on its own and serves its quotes and watchlist as JSON over HTTP on localhost.
+    GET    /quotes              {"quotes":[..],"last_refresh":..,"error":..}
+    GET    /quotes/<code>       one quote
+    GET    /watchlist           [{"code":..,"quantity":..,"cost":..}]
+    POST   /watchlist           add {"code":..} (any format taken by import), or update its holding
+    DELETE /watchlist/<code>    remove a code
+    GET    /stream[?codes=<code>,..]
+                                Server-Sent Events of the subscribed codes (all by default, any format taken by import):
+                                a "snapshot" of their quotes, then a "quote" with the changed fields after each refresh,
+                                and a "removed" when a code leaves the watchlist
+    GET    /metrics             metrics of the quotes and fetches for Prometheus
+Errors are {"error":..} with a 4xx status.
+Requests are answered by a few handler threads. Reads go to the quotes the App shares, without its lock,
+so they do not wait for a tick or for a change of the watchlist being saved.
+*/
+use std::{collections::HashMap, io::Write, net::SocketAddr, sync::{Arc, Mutex, mpsc::{channel, Sender}}, thread, time::Duration};
+
+use chrono::{DateTime, FixedOffset};
+use serde_json::{Map, Value, json};
+use tiny_http::{Header, Request, Response};
+
+use crate::{App, DynResult, Stock, metrics, store::Entry, transfer};
+
+pub const PORT: u16=8080;
+// a comment is sent to idle streams this often (in ticks), so closed clients are noticed
+pub const HEARTBEAT_TICKS: u64=15;
+// number of threads answering requests
+pub const HANDLERS: usize=4;
+
+fn error(status: u16, message: &str) -> (u16, Value) {
+    (status, json!({"error": message}))
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split('?').next().unwrap_or("").split('/').filter(|s| !s.is_empty()).collect()
+}
+
+// the parts of the App read by requests, shared with it
+#[derive(Clone)]
+pub struct View {
+    pub stocks: Arc<Mutex<Vec<Stock>>>,
+    pub last_refresh: Arc<Mutex<DateTime<FixedOffset>>>,
+    pub error: Arc<Mutex<String>>,
+    pub metrics: Arc<Mutex<metrics::Metrics>>,
+}
+
+impl View {
+    pub fn of(app: &App) -> Self {
+        Self { stocks: app.stocks.clone(), last_refresh: app.last_refresh.clone(), error: app.error.clone(), metrics: app.metrics.clone() }
+    }
+}
+
+// answer a read request, None when the request is not a read
+pub fn read(view: &View, method: &str, path: &str) -> Option<(u16, Value)> {
+    Some(match (method, segments(path).as_slice()) {
+        ("GET", ["quotes"]) => {
+            let quotes: Vec<Value> = view.stocks.lock().unwrap().iter().map(Stock::to_json).collect();
+            (200, json!({
+                "quotes": quotes,
+                "last_refresh": view.last_refresh.lock().unwrap().to_rfc3339(),
+                "error": view.error.lock().unwrap().clone(),
+            }))
+        }
+        ("GET", ["quotes", code]) => match view.stocks.lock().unwrap().iter().find(|s| s.code == *code) {
+            Some(stock) => (200, stock.to_json()),
+            None => error(404, &format!("{} is not in the watchlist", code)),
+        },
+        ("GET", ["watchlist"]) => {
+            let entries: Vec<Entry> = view.stocks.lock().unwrap().iter().map(|s| s.entry()).collect();
+            (200, json!(entries))
+        }
+        _ => return None,
+    })
+}
+
+// answer a request changing the watchlist, returning the status and the json body
+pub fn route(app: &mut App, method: &str, path: &str, body: &str) -> (u16, Value) {
+    match (method, segments(path).as_slice()) {
+        ("POST", ["watchlist"]) => {
+            let mut entry: Entry = match serde_json::from_str(body) {
+                Ok(entry) => entry,
+                Err(err) => return error(400, &format!("invalid entry: {}", err)),
+            };
+            entry.code = match transfer::normalize_code(&entry.code) {
+                Ok(code) => code,
+                Err(err) => return error(400, &err),
+            };
+            match app.add_entry(entry.clone()) {
+                Ok(true) => (201, json!(entry)),
+                Ok(false) => (200, json!(entry)),
+                Err(err) => error(500, &err.to_string()),
+            }
+        }
+        ("DELETE", ["watchlist", code]) => {
+            // codes are taken in any format, like on POST
+            let code = match transfer::normalize_code(code) {
+                Ok(code) => code,
+                Err(err) => return error(400, &err),
+            };
+            match app.remove_code(&code) {
+                Ok(true) => (200, json!({"code": code})),
+                Ok(false) => error(404, &format!("{} is not in the watchlist", code)),
+                Err(err) => error(500, &err.to_string()),
+            }
+        }
+        (_, ["quotes"] | ["quotes", _] | ["watchlist"] | ["watchlist", _]) => error(405, "method not allowed"),
+        _ => error(404, "not found"),
+    }
+}
+
+// answer with the status and the json body
+fn respond(request: Request, (status, json): (u16, Value)) {
+    let response = Response::from_string(json.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    // the client may be gone already, nothing to do then
+    request.respond(response).unwrap_or_default();
+}
+
+// format a Server-Sent Event
+fn sse(event: &str, data: &Value) -> String {
+    format!("event: {}\ndata: {}\n\n", event, data)
+}
+
+// a client of the stream, None for all codes
+struct Subscriber {
+    codes: Option<Vec<String>>,
+    sender: Sender<String>,
+}
+
+impl Subscriber {
+    fn wants(&self, code: &str) -> bool {
+        self.codes.as_ref().is_none_or(|codes| codes.iter().any(|c| c == code))
+    }
+}
+
+// the streams of the server, fed with the changes of the quotes
+#[derive(Default)]
+pub struct Hub {
+    subscribers: Mutex<Vec<Subscriber>>,
+    // quotes at the last publish by code
+    last: Mutex<HashMap<String, Map<String, Value>>>,
+    publishes: Mutex<u64>,
+}
+
+impl Hub {
+    // send the changes since the last publish to the subscribers of each code, called every tick
+    // clients that went away are dropped
+    pub fn publish(&self, stocks: &[Stock]) {
+        let mut last = self.last.lock().unwrap();
+        let mut events: Vec<(String, String)> = Vec::new();
+        let mut current = HashMap::new();
+        for stock in stocks {
+            let Value::Object(quote) = stock.to_json() else { continue };
+            let mut diff: Map<String, Value> = match last.get(&stock.code) {
+                Some(old) => quote.iter().filter(|(key, value)| old.get(*key) != Some(value)).map(|(k, v)| (k.clone(), v.clone())).collect(),
+                None => quote.clone(),
+            };
+            if !diff.is_empty() {
+                diff.insert(String::from("code"), json!(stock.code));
+                events.push((stock.code.clone(), sse("quote", &Value::Object(diff))));
+            }
+            current.insert(stock.code.clone(), quote);
+        }
+        for code in last.keys().filter(|code| !current.contains_key(*code)) {
+            events.push((code.clone(), sse("removed", &json!({"code": code}))));
+        }
+        *last = current;
+        let mut publishes = self.publishes.lock().unwrap();
+        *publishes += 1;
+        let heartbeat = publishes.is_multiple_of(HEARTBEAT_TICKS);
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            let mut alive = true;
+            for (_, event) in events.iter().filter(|(code, _)| subscriber.wants(code)) {
+                alive = alive && subscriber.sender.send(event.clone()).is_ok();
+            }
+            if heartbeat {
+                alive = alive && subscriber.sender.send(String::from(": ping\n\n")).is_ok();
+            }
+            alive
+        });
+    }
+
+    // number of connected streams
+    pub fn len(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// cloned into each handler thread
+#[derive(Clone)]
+pub struct Server {
+    http: Arc<tiny_http::Server>,
+    app: Arc<Mutex<App>>,
+    view: View,
+    hub: Arc<Hub>,
+}
+
+impl Server {
+    // listen on the address, like "127.0.0.1:8080" (port 0 picks a free port)
+    pub fn bind(app: Arc<Mutex<App>>, addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let http = tiny_http::Server::http(addr).map_err(|err| format!("cannot listen on {}: {}", addr, err))?;
+        let view = View::of(&app.lock().unwrap());
+        Ok(Self { http: Arc::new(http), app, view, hub: Arc::new(Hub::default()) })
+    }
+
+    // the streams, to publish the changes of the quotes to
+    pub fn hub(&self) -> Arc<Hub> {
+        self.hub.clone()
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    // answer requests on HANDLERS threads, this one included, so a slow request does not hold up the others
+    pub fn run(&self) {
+        for _ in 1..HANDLERS {
+            let server = self.clone();
+            thread::spawn(move || server.answer());
+        }
+        self.answer();
+    }
+
+    fn answer(&self) {
+        for request in self.http.incoming_requests() {
+            self.handle(request);
+        }
+    }
+
+    fn handle(&self, mut request: Request) {
+        if request.method().as_str() == "GET" && request.url().split('?').next() == Some("/stream") {
+            return self.stream(request);
+        }
+        if request.method().as_str() == "GET" && request.url().split('?').next() == Some("/metrics") {
+            let metrics = self.view.metrics.lock().unwrap().clone();
+            let text = metrics::render(&self.view.stocks.lock().unwrap(), &metrics);
+            let response = Response::from_string(text)
+                .with_header(Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap());
+            request.respond(response).unwrap_or_default();
+            return;
+        }
+        let mut body = String::new();
+        let answer = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => read(&self.view, request.method().as_str(), request.url())
+                .unwrap_or_else(|| route(&mut self.app.lock().unwrap(), request.method().as_str(), request.url(), &body)),
+            Err(err) => error(400, &err.to_string()),
+        };
+        respond(request, answer);
+    }
+
+    // open a Server-Sent Events stream to the client, starting with a snapshot of the subscribed quotes
+    // the client gets its own writer thread, so a slow client does not hold up the others
+    fn stream(&self, request: Request) {
+        // codes are taken in any format, like on POST /watchlist, and a bad one is refused before the stream starts
+        let codes = request.url().split_once('?')
+            .and_then(|(_, query)| query.split('&').find_map(|param| param.strip_prefix("codes=")))
+            .map(|codes| codes.split(',').filter(|c| !c.is_empty()).map(transfer::normalize_code).collect::<Result<Vec<String>, String>>())
+            .transpose();
+        let codes = match codes {
+            Ok(codes) => codes,
+            Err(err) => return respond(request, error(400, &err)),
+        };
+        let (sender, receiver) = channel();
+        let subscriber = Subscriber { codes, sender };
+        let snapshot: Vec<Value> = self.view.stocks.lock().unwrap().iter()
+            .filter(|stock| subscriber.wants(&stock.code))
+            .map(Stock::to_json)
+            .collect();
+        subscriber.sender.send(sse("snapshot", &json!(snapshot))).unwrap_or_default();
+        self.hub.subscribers.lock().unwrap().push(subscriber);
+        // write the head by hand, the responses of tiny_http buffer the body
+        let mut writer = request.into_writer();
+        thread::spawn(move || {
+            let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n";
+            if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                return;
+            }
+            // ends when the hub drops the sender, or the client goes away
+            for event in receiver {
+                if writer.write_all(event.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+// serve the App on localhost, ticking it like the TUI does, until the process is killed
+pub fn serve(app: App, port: u16) -> DynResult {
+    if !app.notice.is_empty() {
+        eprintln!("{}", app.notice);
+    }
+    let app = Arc::new(Mutex::new(app));
+    let server = Server::bind(app.clone(), &format!("127.0.0.1:{}", port))?;
+    println!("serving on http://127.0.0.1:{}", port);
+    let hub = server.hub();
+    let stocks = server.view.stocks.clone();
+    // start from the quotes at hand, so the first streams are not sent them again as changes
+    hub.publish(&stocks.lock().unwrap().clone());
+    thread::spawn(move || server.run());
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        {
+            let mut app = app.lock().unwrap();
+            if app.tick() {
+                app.refresh_stocks();
+            }
+        }
+        // published from a copy, so neither the App nor its quotes stay locked while the streams are fed
+        let quotes = stocks.lock().unwrap().clone();
+        hub.publish(&quotes);
+    }
+}
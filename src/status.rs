@@ -0,0 +1,136 @@
+/*
+One-line summary of some quotes, for the status bar of tmux and the like:
+    stock status-line [--codes <code>,..] [--format <template>] [--tmux] [--max-age <secs>]
+The template is filled once per code and the results are joined by spaces. Fields are written {name} or {name:spec}:
+    code, title, price, open, high, low, yestclose, change (price - yestclose), percent (in %), volume
+the spec is an optional + to always show the sign, then an optional .N for the decimals (2 by default, 0 for volume),
+e.g. "{title} {price} {percent:+.2}%". With --tmux a rising quote is wrapped in #[fg=red]..#[default], a falling one in green.
+Quotes come from the cache while they are younger than max-age (60 s by default), the others are fetched and cached,
+so no process has to keep running. If the fetch fails the older cached quotes are shown.
+*/
+use std::{error::Error, str::FromStr};
+
+use crate::{BATCH_SIZE, Config, Stock, apply_quotes, cache, net::HttpConfig, provider::Provider};
+
+pub const DEFAULT_FORMAT: &str="{title} {price} {percent:+.2}%";
+pub const MAX_AGE_SECS: i64=60;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field { name: String, sign: bool, decimals: Option<usize> },
+}
+
+// a parsed format template
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+const FIELDS: [&str; 10] = ["code", "title", "price", "open", "high", "low", "yestclose", "change", "percent", "volume"];
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| format!("unclosed {{ in {}", template))? + start;
+            let field = &rest[start + 1..end];
+            let (name, spec) = field.split_once(':').unwrap_or((field, ""));
+            if !FIELDS.contains(&name) {
+                return Err(format!("unknown field {{{}}}, expected one of {}", name, FIELDS.join(", ")));
+            }
+            let (sign, decimals) = match spec.strip_prefix('+') {
+                Some(decimals) => (true, decimals),
+                None => (false, spec),
+            };
+            let decimals = match decimals {
+                "" => None,
+                decimals => Some(decimals.strip_prefix('.').and_then(|d| d.parse().ok())
+                    .ok_or_else(|| format!("invalid spec {} of {{{}}}, expected like +.2", spec, name))?),
+            };
+            parts.push(Part::Field { name: name.to_string(), sign, decimals });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl Template {
+    // fill the template with a stock, colored for tmux if asked
+    pub fn render(&self, stock: &Stock, tmux: bool) -> String {
+        let mut out = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Field { name, .. } if name == "code" => out.push_str(&stock.code),
+                Part::Field { name, .. } if name == "title" => out.push_str(&stock.title),
+                Part::Field { name, sign, decimals } => {
+                    let value = number(stock, name);
+                    let decimals = decimals.unwrap_or(if name == "volume" { 0 } else { 2 });
+                    out.push_str(&if *sign { format!("{:+.*}", decimals, value) } else { format!("{:.*}", decimals, value) });
+                }
+            }
+        }
+        // red for rising and green for falling, like the TUI
+        match stock.percent {
+            p if tmux && p > 0.0 => format!("#[fg=red]{}#[default]", out),
+            p if tmux && p < 0.0 => format!("#[fg=green]{}#[default]", out),
+            _ => out,
+        }
+    }
+}
+
+// value of a numeric field
+fn number(stock: &Stock, name: &str) -> f64 {
+    match name {
+        "price" => stock.price,
+        "open" => stock.open,
+        "high" => stock.high,
+        "low" => stock.low,
+        "yestclose" => stock.yestclose,
+        "change" => stock.price - stock.yestclose,
+        "percent" => stock.percent * 100.0,
+        _ => stock.volume,
+    }
+}
+
+// the quotes of the codes, cached in the cache file of the config when fresh and fetched otherwise
+// fails only if some code has neither a fetched nor a cached quote
+pub fn quotes(config: &Config, codes: &[String], max_age: i64, provider: &dyn Provider) -> Result<Vec<Stock>, Box<dyn Error>> {
+    let mut stocks: Vec<Stock> = codes.iter().map(|code| Stock::new(code)).collect();
+    let cached = cache::load(&config.cache_path);
+    cache::apply(&mut stocks, &cached);
+    let now = (config.clock)();
+    let outdated: Vec<String> = codes.iter()
+        .filter(|code| cached.get(*code).is_none_or(|quote| (now - quote.saved).num_seconds() > max_age))
+        .cloned()
+        .collect();
+    let http = HttpConfig::from_env();
+    for batch in outdated.chunks(BATCH_SIZE) {
+        match provider.fetch(batch, &http) {
+            Ok(quotes) => apply_quotes(&mut stocks, batch, &quotes, 0),
+            // the older cached quotes are shown then, if every code has one
+            Err(_) if batch.iter().all(|code| cached.contains_key(code)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    if !outdated.is_empty() {
+        cache::save(&config.cache_path, &stocks, now)?;
+    }
+    Ok(stocks)
+}
+
+// the status line of the codes
+pub fn status_line(config: &Config, codes: &[String], template: &Template, tmux: bool, max_age: i64, provider: &dyn Provider) -> Result<String, Box<dyn Error>> {
+    let stocks = quotes(config, codes, max_age, provider)?;
+    Ok(stocks.iter().map(|stock| template.render(stock, tmux)).collect::<Vec<String>>().join(" "))
+}
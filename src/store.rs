@@ -0,0 +1,304 @@
+/*
+The data file keeping the watchlist, ~/.stocks.json by default.
+    version 1: {"stocks":[{"code":"0600000"}]}
+    version 2: {"version":2,"stocks":[{"code":"0600000","quantity":100,"cost":7.5}]}
+               quantity and cost of holdings are optional
+Older files are migrated step by step when loaded.
+Saving writes a temp file and renames it over the data file, so a crash never leaves half a file,
+and the previous file is kept as a rolling backup (.bak.1 is the newest).
+Several instances of the app can share the file: they hold an advisory lock on a .lock file next to it
+while reading or writing, and merge their changes with the ones written by others.
+*/
+use std::{fmt, fs::{self, File, OpenOptions}, hash::{DefaultHasher, Hash, Hasher}, io::{self, Write}, path::{Path, PathBuf}};
+
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value, json};
+
+pub const DB_VERSION: u64=2;
+// number of rolling backups to keep
+pub const BACKUPS: usize=3;
+
+// each migration turns version n (index n - 1) into version n + 1
+type Migration = fn(Map<String, Value>) -> Result<Map<String, Value>, String>;
+const MIGRATIONS: [Migration; 1] = [v1_to_v2];
+
+// version 1 had no version field
+fn v1_to_v2(mut json: Map<String, Value>) -> Result<Map<String, Value>, String> {
+    json.insert(String::from("version"), json!(2));
+    Ok(json)
+}
+
+// one stock of the watchlist as kept in the data file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub code: String,
+    // number of shares held, None when only watched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<f64>,
+    // average cost per share of the holding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl Entry {
+    pub fn new(code: &str) -> Self {
+        Self { code: code.to_string(), quantity: None, cost: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(PathBuf, io::Error),
+    // the file exists, but cannot be understood
+    Corrupt(PathBuf, String),
+    // the file was written by a newer version of the app
+    TooNew(PathBuf, u64),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(path, err) => write!(f, "cannot access {}: {}", path.display(), err),
+            StoreError::Corrupt(path, err) => write!(f, "{} is corrupt ({}), fix it or restore a backup like {}",
+                path.display(), err, backup_path(path, 1).display()),
+            StoreError::TooNew(path, version) => write!(f, "{} has version {}, but this app only knows up to version {}",
+                path.display(), version, DB_VERSION),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+pub fn backup_path(path: &Path, n: usize) -> PathBuf {
+    PathBuf::from(format!("{}.bak.{}", path.display(), n))
+}
+
+// read the entries from the data file, migrating older versions
+// a missing file is an empty watchlist
+pub fn load(path: &Path) -> Result<Vec<Entry>, StoreError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(StoreError::Io(path.to_path_buf(), err)),
+    };
+    from_json(path, &content)
+}
+
+// parse the content of a data file, path is only used in errors
+pub fn from_json(path: &Path, content: &str) -> Result<Vec<Entry>, StoreError> {
+    let corrupt = |err: String| StoreError::Corrupt(path.to_path_buf(), err);
+    let mut json: Map<String, Value> = serde_json::from_str(content).map_err(|err| corrupt(err.to_string()))?;
+    let version = match json.get("version") {
+        None => 1,
+        Some(version) => version.as_u64().ok_or_else(|| corrupt(String::from("version is not a number")))?,
+    };
+    if version > DB_VERSION {
+        return Err(StoreError::TooNew(path.to_path_buf(), version));
+    }
+    for migration in MIGRATIONS.iter().skip(version.saturating_sub(1) as usize) {
+        json = migration(json).map_err(corrupt)?;
+    }
+    json.get("stocks").unwrap_or(&json!([])).as_array()
+        .ok_or_else(|| corrupt(String::from("stocks is not a list")))?
+        .iter()
+        .map(|s| serde_json::from_value(s.clone()).map_err(|err| corrupt(format!("invalid stock {}: {}", s, err))))
+        .collect()
+}
+
+// the content of a data file with the entries
+pub fn to_json(entries: &[Entry]) -> String {
+    serde_json::to_string(&json!({"version": DB_VERSION, "stocks": entries})).unwrap()
+}
+
+// write the entries into the data file atomically, keeping the previous file as a backup
+pub fn save(path: &Path, entries: &[Entry]) -> Result<(), StoreError> {
+    let io_err = |err: io::Error| StoreError::Io(path.to_path_buf(), err);
+    if path.exists() {
+        rotate_backups(path).map_err(io_err)?;
+    }
+    write_atomic(path, to_json(entries).as_bytes()).map_err(io_err)
+}
+
+// replace the file with the content at once, readers see either the old or the new content
+pub fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    // the temp file is in the same directory, so the rename does not cross file systems
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+// shift .bak.1 to .bak.2 and so on, then copy the data file to .bak.1
+fn rotate_backups(path: &Path) -> io::Result<()> {
+    for n in (1..BACKUPS).rev() {
+        if backup_path(path, n).exists() {
+            fs::rename(backup_path(path, n), backup_path(path, n + 1))?;
+        }
+    }
+    fs::copy(path, backup_path(path, 1))?;
+    Ok(())
+}
+
+// lock the data file for reading (shared) or writing (exclusive), released when the returned file is dropped
+// the lock is on a separate file, because saving replaces the data file itself
+pub fn lock(path: &Path, exclusive: bool) -> Result<File, StoreError> {
+    let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+    let io_err = |err: io::Error| StoreError::Io(lock_path.clone(), err);
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path).map_err(io_err)?;
+    if exclusive { file.lock() } else { file.lock_shared() }.map_err(io_err)?;
+    Ok(file)
+}
+
+// hash of the content of the data file, to notice when another instance wrote it
+// the modification time alone misses writes within its resolution, e.g. two saves in the same second
+pub fn fingerprint(path: &Path) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    fs::read(path).ok()?.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+// three-way merge of watchlists
+// base is the list both sides started from, ours and theirs are the lists after the changes of each side
+// entries added on either side are kept and entries removed on either side are dropped,
+// when only one side changed, its order is kept
+// an entry changed on our side (e.g. its quantity) wins over a change on their side, but not over a removal on it
+pub fn merge(base: &[Entry], ours: &[Entry], theirs: &[Entry]) -> Vec<Entry> {
+    if theirs == base {
+        return ours.to_vec();
+    }
+    if ours == base {
+        return theirs.to_vec();
+    }
+    // the code was in the base but is not on the side, a removal even if the other side changed the entry
+    let removed = |side: &[Entry], entry: &Entry| base.iter().any(|e| e.code == entry.code) && !side.iter().any(|e| e.code == entry.code);
+    let mut merged: Vec<Entry> = theirs.iter()
+        // removed by us, entries changed by us keep their place and are replaced below
+        .filter(|entry| !removed(ours, entry))
+        .cloned()
+        .collect();
+    for entry in ours {
+        // added or changed by us, unless they removed it
+        if !base.contains(entry) && !removed(theirs, entry) {
+            match merged.iter().position(|e| e.code == entry.code) {
+                Some(i) => merged[i] = entry.clone(),
+                None => merged.push(entry.clone()),
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(codes: &[&str]) -> Vec<Entry> {
+        codes.iter().map(|code| Entry::new(code)).collect()
+    }
+
+    #[test]
+    fn version_1_files_are_migrated() {
+        let path = Path::new("stocks.json");
+        assert_eq!(from_json(path, r#"{"stocks":[{"code":"0600000"},{"code":"1000001"}]}"#).unwrap(), entries(&["0600000", "1000001"]));
+        let holding = from_json(path, r#"{"version":2,"stocks":[{"code":"0600000","quantity":100,"cost":7.5}]}"#).unwrap();
+        assert_eq!((holding[0].quantity, holding[0].cost), (Some(100.0), Some(7.5)));
+        assert!(from_json(path, "{}").unwrap().is_empty());
+        assert_eq!(to_json(&entries(&["0600000"])), r#"{"stocks":[{"code":"0600000"}],"version":2}"#);
+    }
+
+    #[test]
+    fn bad_files_are_errors() {
+        let path = Path::new("stocks.json");
+        for content in ["not json", r#"{"version":"2"}"#, r#"{"stocks":{}}"#, r#"{"stocks":[{"quantity":1}]}"#] {
+            assert!(matches!(from_json(path, content), Err(StoreError::Corrupt(..))), "{}", content);
+        }
+        assert!(matches!(from_json(path, r#"{"version":3,"stocks":[]}"#), Err(StoreError::TooNew(_, 3))));
+        // the error tells where the backup is
+        assert!(from_json(path, "not json").unwrap_err().to_string().contains("stocks.json.bak.1"));
+    }
+
+    #[test]
+    fn saves_keep_rolling_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stocks.json");
+        assert_eq!(backup_path(&path, 2), dir.path().join("stocks.json.bak.2"));
+        let codes = ["0600000", "1000001", "0601318", "0600519", "1000858"];
+        for code in codes {
+            save(&path, &entries(&[code])).unwrap();
+        }
+        assert_eq!(load(&path).unwrap(), entries(&["1000858"]));
+        // .bak.1 is the newest, and only BACKUPS are kept
+        for n in 1..=BACKUPS {
+            assert_eq!(load(&backup_path(&path, n)).unwrap(), entries(&[codes[codes.len() - 1 - n]]));
+        }
+        assert!(!backup_path(&path, BACKUPS + 1).exists());
+    }
+
+    #[test]
+    fn saves_replace_the_file_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stocks.json");
+        let tmp = dir.path().join("stocks.json.tmp");
+        save(&path, &entries(&["0600000"])).unwrap();
+        assert!(!tmp.exists());
+        // a save that died before the rename leaves the data file as it was, and the next save goes on
+        fs::write(&tmp, "{\"stocks\":[{\"co").unwrap();
+        assert_eq!(load(&path).unwrap(), entries(&["0600000"]));
+        save(&path, &entries(&["1000001"])).unwrap();
+        assert_eq!(load(&path).unwrap(), entries(&["1000001"]));
+        assert!(!tmp.exists());
+        // a missing file is an empty watchlist, but a file that cannot be read is an error
+        assert!(load(&dir.path().join("missing.json")).unwrap().is_empty());
+        assert!(matches!(load(dir.path()), Err(StoreError::Io(..))));
+    }
+
+    fn holding(code: &str, quantity: f64) -> Entry {
+        Entry { quantity: Some(quantity), ..Entry::new(code) }
+    }
+
+    #[test]
+    fn merges_keep_the_additions_and_removals_of_both_sides() {
+        let base = entries(&["0600000", "1000001"]);
+        // only one side changed, its list wins including its order
+        assert_eq!(merge(&base, &entries(&["1000001", "0600000"]), &base), entries(&["1000001", "0600000"]));
+        assert_eq!(merge(&base, &base, &entries(&["1000001"])), entries(&["1000001"]));
+        // added on each side
+        assert_eq!(merge(&base, &entries(&["0600000", "1000001", "0601318"]), &entries(&["0600000", "1000001", "0600519"])),
+            entries(&["0600000", "1000001", "0600519", "0601318"]));
+        // removed on each side
+        assert_eq!(merge(&base, &entries(&["1000001"]), &entries(&["0600000"])), entries(&[]));
+        // removed by us, added by them
+        assert_eq!(merge(&base, &entries(&["0600000"]), &entries(&["0600000", "1000001", "0601318"])), entries(&["0600000", "0601318"]));
+    }
+
+    #[test]
+    fn merges_of_changed_entries() {
+        let base = vec![holding("0600000", 100.0), Entry::new("1000001")];
+        // changed on both sides, ours wins
+        let merged = merge(&base, &[holding("0600000", 200.0), Entry::new("1000001")], &[holding("0600000", 300.0), Entry::new("1000001")]);
+        assert_eq!(merged, vec![holding("0600000", 200.0), Entry::new("1000001")]);
+        // changed on one side, the other side added a code
+        let merged = merge(&base, &[holding("0600000", 200.0), Entry::new("1000001")], &[holding("0600000", 100.0), Entry::new("1000001"), Entry::new("0601318")]);
+        assert_eq!(merged, vec![holding("0600000", 200.0), Entry::new("1000001"), Entry::new("0601318")]);
+        // removed by them and changed by us, the removal wins rather than the entry coming back
+        let merged = merge(&base, &[holding("0600000", 200.0), Entry::new("1000001"), Entry::new("0601318")], &[Entry::new("1000001")]);
+        assert_eq!(merged, entries(&["1000001", "0601318"]));
+        // and the other way around
+        let merged = merge(&base, &[Entry::new("1000001"), Entry::new("0601318")], &[holding("0600000", 300.0), Entry::new("1000001")]);
+        assert_eq!(merged, entries(&["1000001", "0601318"]));
+    }
+
+    #[test]
+    fn fingerprints_change_with_the_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stocks.json");
+        assert_eq!(fingerprint(&path), None);
+        save(&path, &[holding("0600000", 100.0)]).unwrap();
+        let first = fingerprint(&path);
+        // the same length within the same second, which the modification time would miss
+        save(&path, &[holding("0600000", 200.0)]).unwrap();
+        assert_ne!(fingerprint(&path), first);
+        assert_eq!(fingerprint(&path), fingerprint(&path));
+    }
+}
@@ -0,0 +1,452 @@
+/*
+Import and export of watchlists and holdings.
+Formats:
+    csv     plain list of codes, one per line (the first column is used)
+    json    the data file of this app
+    broker  csv exported by a broker or portfolio tool, with a header row and
+            columns for code, name, quantity and cost, found by their names or a mapping
+Imported codes are normalized to the NetEase format, e.g. "600000.SH" -> "0600000",
+and every row is reported as recognized, normalized or rejected, so a dry run can preview the import.
+In the TUI the file is typed with the options of the command line, like "holdings.csv --format broker --map code=Stock No".
+*/
+use std::{fmt, path::Path, str::FromStr};
+
+use crate::store::{self, Entry, StoreError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+    Broker,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "broker" => Ok(Format::Broker),
+            _ => Err(format!("unknown format {}, use csv, json or broker", s)),
+        }
+    }
+}
+
+impl Format {
+    // guess the format from the file name and content
+    // a csv whose header names a code column of the mapping is a broker export
+    pub fn guess(path: &str, content: &str, mapping: &Mapping) -> Self {
+        if path.to_lowercase().ends_with(".json") || content.trim_start().starts_with('{') {
+            return Format::Json;
+        }
+        let header = content.trim_start_matches('\u{feff}').lines().next().unwrap_or("");
+        let columns: Vec<String> = header.split(',').map(|c| c.trim().trim_matches('"').to_lowercase()).collect();
+        if columns.len() > 1 && mapping.code.iter().any(|name| columns.contains(name)) {
+            Format::Broker
+        } else {
+            Format::Csv
+        }
+    }
+}
+
+// names of the columns of a broker export, any of them may match (case-insensitive)
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    pub code: Vec<String>,
+    pub name: Vec<String>,
+    pub quantity: Vec<String>,
+    pub cost: Vec<String>,
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_lowercase()).collect()
+}
+
+impl Default for Mapping {
+    // headers used by common brokers and portfolio tools
+    fn default() -> Self {
+        Self {
+            code: names(&["code", "symbol", "ticker", "证券代码", "股票代码", "代码"]),
+            name: names(&["name", "证券名称", "股票名称", "名称"]),
+            quantity: names(&["quantity", "qty", "shares", "持仓数量", "股票余额", "证券数量", "持仓"]),
+            cost: names(&["cost", "avg cost", "cost price", "成本价", "参考成本价", "买入均价", "持仓成本"]),
+        }
+    }
+}
+
+impl Mapping {
+    // the default mapping with columns overridden by a spec like "code=Stock No,quantity=Units"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut mapping = Self::default();
+        for pair in spec.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (field, column) = pair.split_once('=').ok_or_else(|| format!("invalid mapping {}, use field=column", pair))?;
+            let column = vec![column.trim().to_lowercase()];
+            match field.trim() {
+                "code" => mapping.code = column,
+                "name" => mapping.name = column,
+                "quantity" => mapping.quantity = column,
+                "cost" => mapping.cost = column,
+                field => return Err(format!("unknown field {}, use code, name, quantity or cost", field)),
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+// a file to import or export with its options, as typed in the TUI
+// the file is everything before the first option, so file and column names may have spaces
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub path: String,
+    pub format: Option<Format>,
+    pub mapping: Mapping,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, String> {
+        let mut parts = input.split(" --");
+        let path = parts.next().unwrap_or("").trim().to_string();
+        if path.is_empty() {
+            return Err(String::from("no file given"));
+        }
+        let mut target = Self { path, format: None, mapping: Mapping::default() };
+        for option in parts {
+            let (name, value) = option.trim().split_once(' ').unwrap_or((option.trim(), ""));
+            match name {
+                "format" => target.format = Some(value.trim().parse()?),
+                "map" => target.mapping = Mapping::parse(value)?,
+                name => return Err(format!("unknown option --{}, use --format or --map", name)),
+            }
+        }
+        Ok(target)
+    }
+}
+
+// how a row of the import was handled
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    // taken as it is
+    Recognized,
+    // taken after the code was converted from the given one
+    Normalized(String),
+    // not taken, for the given reason
+    Rejected(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Row {
+    // line number in the file, starting from 1
+    pub line: usize,
+    pub entry: Option<Entry>,
+    pub name: Option<String>,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.entry.as_ref().map(|e| e.code.as_str()).unwrap_or("-");
+        let holding = match self.entry.as_ref().and_then(|e| e.quantity) {
+            Some(quantity) => format!(" x{}", quantity),
+            None => String::new(),
+        };
+        let name = self.name.as_ref().map(|n| format!(" {}", n)).unwrap_or_default();
+        match &self.outcome {
+            Outcome::Recognized => write!(f, "LINE {}: OK {}{}{}", self.line, code, name, holding),
+            Outcome::Normalized(from) => write!(f, "LINE {}: NORMALIZED {} -> {}{}{}", self.line, from, code, name, holding),
+            Outcome::Rejected(reason) => write!(f, "LINE {}: REJECTED {}", self.line, reason),
+        }
+    }
+}
+
+// result of parsing an import, before it is merged into the store
+#[derive(Clone, Debug, Default)]
+pub struct Preview {
+    pub rows: Vec<Row>,
+}
+
+impl Preview {
+    // the entries to import, without duplicates (the last one wins)
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = Vec::new();
+        for entry in self.rows.iter().filter_map(|row| row.entry.clone()) {
+            match entries.iter().position(|e| e.code == entry.code) {
+                Some(i) => entries[i] = entry,
+                None => entries.push(entry),
+            }
+        }
+        entries
+    }
+
+    pub fn count(&self, f: fn(&Outcome) -> bool) -> usize {
+        self.rows.iter().filter(|row| f(&row.outcome)).count()
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} RECOGNIZED, {} NORMALIZED, {} REJECTED",
+            self.count(|o| *o == Outcome::Recognized),
+            self.count(|o| matches!(o, Outcome::Normalized(_))),
+            self.count(|o| matches!(o, Outcome::Rejected(_))))
+    }
+}
+
+// convert a code to the NetEase format: 0 + 6 digits for Shanghai, 1 + 6 digits for Shenzhen
+// accepts "0600000", "600000", "sh600000", "600000.SH", "600000.SS", "sz000001", "000001.SZ"
+pub fn normalize_code(code: &str) -> Result<String, String> {
+    let code = code.trim().to_lowercase();
+    let digits = |s: &str| s.len() == 6 && s.chars().all(|c| c.is_ascii_digit());
+    if code.len() == 7 && (code.starts_with('0') || code.starts_with('1')) && digits(&code[1..]) {
+        return Ok(code);
+    }
+    let (market, number) = if let Some(number) = code.strip_prefix("sh") {
+        (Some('0'), number)
+    } else if let Some(number) = code.strip_prefix("sz") {
+        (Some('1'), number)
+    } else if let Some(number) = code.strip_suffix(".sh").or_else(|| code.strip_suffix(".ss")) {
+        (Some('0'), number)
+    } else if let Some(number) = code.strip_suffix(".sz") {
+        (Some('1'), number)
+    } else {
+        (None, code.as_str())
+    };
+    if !digits(number) {
+        return Err(format!("invalid code {}", code));
+    }
+    // without a market, Shanghai codes start with 5, 6 or 9, the others are Shenzhen
+    let market = market.unwrap_or(if number.starts_with(['5', '6', '9']) { '0' } else { '1' });
+    Ok(format!("{}{}", market, number))
+}
+
+// a row with the code normalized, or rejected
+fn make_row(line: usize, code: &str, name: Option<String>, quantity: Option<f64>, cost: Option<f64>) -> Row {
+    match normalize_code(code) {
+        Ok(normalized) => Row {
+            line,
+            outcome: if normalized == code.trim() { Outcome::Recognized } else { Outcome::Normalized(code.trim().to_string()) },
+            entry: Some(Entry { code: normalized, quantity, cost }),
+            name,
+        },
+        Err(reason) => Row { line, entry: None, name, outcome: Outcome::Rejected(reason) },
+    }
+}
+
+// line of a csv record in the content
+// the reader skips blank lines, so neither the index of the record nor the line of its position (where the skipped lines start) tell
+fn line_of(content: &str, record: &csv::StringRecord, fallback: usize) -> usize {
+    let Some(byte) = record.position().map(|position| position.byte() as usize).filter(|byte| content.is_char_boundary(*byte)) else {
+        return fallback;
+    };
+    let start = content.len() - content[byte..].trim_start_matches(['\r', '\n']).len();
+    content[..start].matches('\n').count() + 1
+}
+
+// parse the content of an import file into a preview of its rows
+pub fn parse(content: &str, format: Format, mapping: &Mapping) -> Result<Preview, String> {
+    let content = content.trim_start_matches('\u{feff}');
+    let mut rows = Vec::new();
+    match format {
+        Format::Json => {
+            // go through the store, so older versions of the data file are migrated
+            let entries = store::from_json(Path::new("the import file"), content).map_err(|err| err.to_string())?;
+            for (i, entry) in entries.into_iter().enumerate() {
+                rows.push(make_row(i + 1, &entry.code, None, entry.quantity, entry.cost));
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(content.as_bytes());
+            for (i, record) in reader.records().enumerate() {
+                let record = record.map_err(|err| err.to_string())?;
+                let code = record.get(0).unwrap_or("").trim();
+                // skip blank lines and a header row
+                if code.is_empty() || (i == 0 && Mapping::default().code.contains(&code.to_lowercase())) {
+                    continue;
+                }
+                rows.push(make_row(line_of(content, &record, i + 1), code, None, None, None));
+            }
+        }
+        Format::Broker => {
+            let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(content.as_bytes());
+            let headers: Vec<String> = reader.headers().map_err(|err| err.to_string())?
+                .iter().map(|h| h.trim().to_lowercase()).collect();
+            let find = |names: &[String]| headers.iter().position(|h| names.contains(h));
+            let code_col = find(&mapping.code).ok_or_else(|| format!("no code column in {:?}, map one with code=<column>", headers))?;
+            let (name_col, quantity_col, cost_col) = (find(&mapping.name), find(&mapping.quantity), find(&mapping.cost));
+            for (i, record) in reader.records().enumerate() {
+                let record = record.map_err(|err| err.to_string())?;
+                // the header is line 1
+                let line = line_of(content, &record, i + 2);
+                let field = |col: Option<usize>| col.and_then(|c| record.get(c)).map(|v| v.trim()).filter(|v| !v.is_empty());
+                let number = |col: Option<usize>| field(col).map(|v| v.replace(',', "").parse::<f64>());
+                let code = field(Some(code_col)).unwrap_or("");
+                if code.is_empty() {
+                    continue;
+                }
+                let row = match (number(quantity_col).transpose(), number(cost_col).transpose()) {
+                    (Ok(quantity), Ok(cost)) => make_row(line, code, field(name_col).map(String::from), quantity, cost),
+                    _ => Row { line, entry: None, name: None, outcome: Outcome::Rejected(format!("invalid quantity or cost for {}", code)) },
+                };
+                rows.push(row);
+            }
+        }
+    }
+    Ok(Preview { rows })
+}
+
+// merge imported entries into the existing ones
+// new codes are appended, and existing codes take the imported holding if it has one
+pub fn merge(existing: &[Entry], imported: &[Entry]) -> Vec<Entry> {
+    let mut merged = existing.to_vec();
+    for entry in imported {
+        match merged.iter_mut().find(|e| e.code == entry.code) {
+            Some(e) => {
+                if entry.quantity.is_some() {
+                    e.quantity = entry.quantity;
+                    e.cost = entry.cost;
+                }
+            }
+            None => merged.push(entry.clone()),
+        }
+    }
+    merged
+}
+
+// export the entries, names are looked up by code for the broker format
+pub fn export(entries: &[Entry], format: Format, name: impl Fn(&str) -> String) -> Result<String, String> {
+    match format {
+        Format::Json => Ok(store::to_json(entries)),
+        Format::Csv => Ok(entries.iter().map(|e| format!("{}\n", e.code)).collect()),
+        Format::Broker => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer.write_record(["code", "name", "quantity", "cost"]).map_err(|err| err.to_string())?;
+            for e in entries {
+                let number = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+                writer.write_record([e.code.clone(), name(&e.code), number(e.quantity), number(e.cost)]).map_err(|err| err.to_string())?;
+            }
+            String::from_utf8(writer.into_inner().map_err(|err| err.to_string())?).map_err(|err| err.to_string())
+        }
+    }
+}
+
+// merge imported entries into the data file, under its lock so running instances are not clobbered
+pub fn import_into(path: &Path, imported: &[Entry]) -> Result<(), StoreError> {
+    let _lock = store::lock(path, true)?;
+    let existing = store::load(path)?;
+    store::save(path, &merge(&existing, imported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(preview: &Preview) -> Vec<String> {
+        preview.entries().into_iter().map(|entry| entry.code).collect()
+    }
+
+    #[test]
+    fn codes_are_normalized_to_the_netease_format() {
+        for (code, normalized) in [
+            ("0600000", "0600000"), ("1000001", "1000001"), ("600000", "0600000"), ("000001", "1000001"), ("510300", "0510300"),
+            ("sh600000", "0600000"), ("SZ000001", "1000001"), ("600000.SH", "0600000"), ("600000.ss", "0600000"),
+            (" 000001.SZ ", "1000001"), ("300750", "1300750"),
+        ] {
+            assert_eq!(normalize_code(code).unwrap(), normalized, "{}", code);
+        }
+        for code in ["", "60000", "6000000", "2600000", "sh60000a", "600000.HK", "hk00700", "AAPL"] {
+            assert!(normalize_code(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn formats_are_guessed_from_the_name_and_the_header() {
+        let mapping = Mapping::default();
+        assert_eq!(Format::guess("list.json", "", &mapping), Format::Json);
+        assert_eq!(Format::guess("list.txt", "  {\"stocks\":[]}", &mapping), Format::Json);
+        assert_eq!(Format::guess("list.csv", "600000\n000001\n", &mapping), Format::Csv);
+        assert_eq!(Format::guess("list.csv", "code\n600000\n", &mapping), Format::Csv);
+        assert_eq!(Format::guess("holdings.csv", "\u{feff}\"Symbol\",Name,Qty\n", &mapping), Format::Broker);
+        assert_eq!(Format::guess("holdings.csv", "证券代码,证券名称,持仓数量\n", &mapping), Format::Broker);
+        // the mapped code column counts too
+        assert_eq!(Format::guess("holdings.csv", "Stock No,Units\n", &mapping), Format::Csv);
+        assert_eq!(Format::guess("holdings.csv", "Stock No,Units\n", &Mapping::parse("code=Stock No").unwrap()), Format::Broker);
+        assert_eq!("Broker".parse::<Format>().unwrap(), Format::Broker);
+        assert!("xls".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn mappings_override_the_column_names() {
+        let mapping = Mapping::parse("code=Stock No, quantity = Units").unwrap();
+        assert_eq!((mapping.code, mapping.quantity), (vec![String::from("stock no")], vec![String::from("units")]));
+        assert_eq!(mapping.name, Mapping::default().name);
+        assert!(Mapping::parse("").is_ok());
+        assert!(Mapping::parse("code").unwrap_err().contains("field=column"));
+        assert!(Mapping::parse("price=Last").unwrap_err().contains("unknown field"));
+    }
+
+    #[test]
+    fn csv_rows_are_reported() {
+        let preview = parse("code\nsh600000\n\n1000001\nnope\n", Format::Csv, &Mapping::default()).unwrap();
+        assert_eq!(codes(&preview), vec!["0600000", "1000001"]);
+        let outcomes: Vec<(usize, Outcome)> = preview.rows.iter().map(|row| (row.line, row.outcome.clone())).collect();
+        assert_eq!(outcomes, vec![
+            (2, Outcome::Normalized(String::from("sh600000"))),
+            (4, Outcome::Recognized),
+            (5, Outcome::Rejected(String::from("invalid code nope"))),
+        ]);
+        assert_eq!(preview.summary(), "1 RECOGNIZED, 1 NORMALIZED, 1 REJECTED");
+    }
+
+    #[test]
+    fn json_imports_go_through_the_store() {
+        let preview = parse(r#"{"stocks":[{"code":"0600000"},{"code":"600000.SH","quantity":100}]}"#, Format::Json, &Mapping::default()).unwrap();
+        // the last duplicate wins
+        assert_eq!(preview.entries(), vec![Entry { code: String::from("0600000"), quantity: Some(100.0), cost: None }]);
+        assert!(parse("{", Format::Json, &Mapping::default()).is_err());
+    }
+
+    #[test]
+    fn broker_columns_are_found_by_name_or_mapping() {
+        let content = "\u{feff}证券代码,证券名称,持仓数量,成本价\n600000,浦发银行,\"1,000\",7.5\n\n000001.SZ,平安银行,200,abc\n,,,\n";
+        let preview = parse(content, Format::Broker, &Mapping::default()).unwrap();
+        assert_eq!(preview.entries(), vec![Entry { code: String::from("0600000"), quantity: Some(1000.0), cost: Some(7.5) }]);
+        assert_eq!(preview.rows[0].name.as_deref(), Some("浦发银行"));
+        assert_eq!(preview.rows[1].line, 4);
+        assert!(matches!(&preview.rows[1].outcome, Outcome::Rejected(reason) if reason.contains("invalid quantity or cost")));
+
+        let content = "Stock No,Units\nsz000001,300\n";
+        assert!(parse(content, Format::Broker, &Mapping::default()).unwrap_err().contains("no code column"));
+        let mapping = Mapping::parse("code=Stock No,quantity=Units").unwrap();
+        assert_eq!(parse(content, Format::Broker, &mapping).unwrap().entries(),
+            vec![Entry { code: String::from("1000001"), quantity: Some(300.0), cost: None }]);
+    }
+
+    #[test]
+    fn merging_keeps_existing_codes_and_takes_imported_holdings() {
+        let existing = vec![Entry { code: String::from("0600000"), quantity: Some(100.0), cost: Some(7.0) }, Entry::new("1000001")];
+        let imported = vec![
+            Entry::new("0600000"),
+            Entry { code: String::from("1000001"), quantity: Some(50.0), cost: Some(11.0) },
+            Entry::new("0601318"),
+        ];
+        assert_eq!(merge(&existing, &imported), vec![
+            // an import without a holding does not drop the one there
+            Entry { code: String::from("0600000"), quantity: Some(100.0), cost: Some(7.0) },
+            Entry { code: String::from("1000001"), quantity: Some(50.0), cost: Some(11.0) },
+            Entry::new("0601318"),
+        ]);
+        assert_eq!(merge(&existing, &[]), existing);
+    }
+
+    #[test]
+    fn targets_take_the_options_of_the_command_line() {
+        let target: Target = "my holdings.csv --format broker --map code=Stock No,quantity=Units".parse().unwrap();
+        assert_eq!((target.path.as_str(), target.format), ("my holdings.csv", Some(Format::Broker)));
+        assert_eq!(target.mapping.code, vec![String::from("stock no")]);
+        let target: Target = " codes.csv ".parse().unwrap();
+        assert_eq!((target.path.as_str(), target.format), ("codes.csv", None));
+        assert!("".parse::<Target>().is_err());
+        assert!("codes.csv --format xls".parse::<Target>().is_err());
+        assert!("codes.csv --dry-run".parse::<Target>().unwrap_err().contains("unknown option"));
+    }
+}
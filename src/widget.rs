@@ -1,12 +1,24 @@
-use tui::{layout::{Rect, Layout, Direction, Constraint, Alignment}, 
-widgets::{Paragraph, Block, Borders, BorderType, List, ListItem}, 
+use std::collections::VecDeque;
+
+use tui::{Frame, backend::Backend, layout::{Rect, Layout, Direction, Constraint, Alignment}, 
+widgets::{Paragraph, Block, Borders, BorderType, Clear, List, ListItem, ListState, Wrap}, 
 style::{Style, Color, Modifier}, text::{Spans, Span}};
 
-use crate::{App, Stock, AppState};
+use chrono::{DateTime, FixedOffset, Local};
+
+use crate::{App, Stock, AppState, AppView, calendar, transfer::Outcome};
 use unicode_width::UnicodeWidthStr;
 
 
 const VERSION:&str = env!("CARGO_PKG_VERSION");
+// width of the sparkline in the stock list
+const SPARK_WIDTH:usize = 10;
+// block characters from low to high
+const SPARK_BARS:[char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+// frames of the spinner shown while a refresh is in flight
+const SPINNER:[char; 4] = ['|', '/', '-', '\\'];
+// a percent of this size or more gets the strongest heatmap color (daily limit of A-shares)
+const HEAT_MAX:f64 = 0.1;
 
 
 // calculate the area of the screen window, in order for being used later to render
@@ -53,18 +65,73 @@ pub fn main_chunks(area: Rect) -> Vec<Rect> {
         ].as_ref())
         .split(popup[1]);       
     
-    vec!(parent[0], center[0], center[1], parent[2], popline[1])    
+    // the last one is the whole center used by the heatmap
+    vec!(parent[0], center[0], center[1], parent[2], popline[1], parent[1])    
+}
+
+// split the area into an even grid of tiles, one per stock
+pub fn heatmap_chunks(area: Rect, count: usize) -> Vec<Rect> {
+    if count == 0 {
+        return vec!();
+    }
+    // as square as possible
+    let cols = (count as f64).sqrt().ceil() as usize;
+    let rows = count.div_ceil(cols);
+    let lines = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Ratio(1, rows as u32); rows])
+        .split(area);
+    lines.iter()
+        .flat_map(|line| Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(vec![Constraint::Ratio(1, cols as u32); cols])
+            .split(*line))
+        .take(count)
+        .collect()
+}
+
+// TUI for one tile of the heatmap, colored by the intensity of percent
+pub fn heatmap_tile(stock: &Stock, selected: bool) -> Paragraph<'_> {
+    // scale from a dim to a bright color, red for up and green for down
+    let heat = (stock.percent.abs() / HEAT_MAX).min(1.0);
+    let level = (60.0 + heat * 195.0) as u8;
+    let bg = if stock.percent < 0.0 { Color::Rgb(0, level, 0) } else if stock.percent > 0.0 { Color::Rgb(level, 0, 0) } else { Color::DarkGray };
+    Paragraph::new(format!("{}\n{:+.2}%", stock.title, stock.percent * 100.0))
+        .alignment(Alignment::Center)
+        .style(Style::default().bg(bg).fg(Color::White))
+        .block(Block::default()
+            .borders(Borders::ALL)
+            .border_style(if selected {
+                Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            })
+            .border_type(BorderType::Plain))
 }
 
 // TUI for stock list
-pub fn stock_list(stocks: &Vec<Stock>) -> List {
+// stocks with quotes before stale_before are dimmed
+pub fn stock_list(stocks: &[Stock], stale_before: Option<DateTime<FixedOffset>>) -> List<'_> {
     let items: Vec<_> = stocks.iter()
         .map(|stock| {
-            ListItem::new(Spans::from(vec![
-                Span::styled(format!("{:+.2}% ",stock.percent * 100.0),
-                    Style::default().fg(if stock.percent < 0.0 {Color::Green} else {Color::Red})),
+            // cached quotes are not live, so they are always shown as stale
+            let stale = stock.cached || stale_before.is_some_and(|before| stock.is_stale(before));
+            // arrow shows the direction of the latest price change
+            let arrow = match stock.direction() { 1 => "▲", -1 => "▼", _ => " " };
+            let color = if stock.percent < 0.0 {Color::Green} else {Color::Red};
+            // flash the background in the direction color for a few ticks after a change
+            let style = if stock.flash > 0 {
+                Style::default().fg(Color::Black).bg(if stock.direction() < 0 {Color::Green} else {Color::Red})
+            } else {
+                Style::default().fg(color)
+            };
+            let item = ListItem::new(Spans::from(vec![
+                Span::styled(format!("{}{:+.2}% ", arrow, stock.percent * 100.0), style),
+                Span::styled(format!("{} ", sparkline(&stock.slice, SPARK_WIDTH)), Style::default().fg(color)),
                 Span::styled(stock.title.clone(),Style::default()),
-                ]))
+                Span::styled(if stock.cached { " (CACHED)" } else { "" }, Style::default().fg(Color::DarkGray)),
+                ]));
+            if stale { item.style(Style::default().add_modifier(Modifier::DIM)) } else { item }
         }).collect();
 
     List::new(items)
@@ -81,16 +148,63 @@ pub fn stock_list(stocks: &Vec<Stock>) -> List {
             .add_modifier(Modifier::BOLD))
 }
 
+// draw the samples as a line of block characters with the given width
+// when there are more samples than width, each char shows the last sample of its bucket
+pub fn sparkline(samples: &VecDeque<f64>, width: usize) -> String {
+    if samples.is_empty() || width == 0 {
+        return " ".repeat(width);
+    }
+    let points: Vec<f64> = if samples.len() > width {
+        (1..=width).map(|i| samples[i * samples.len() / width - 1]).collect()
+    } else {
+        samples.iter().copied().collect()
+    };
+    let min = points.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = points.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let line: String = points.iter()
+        .map(|p| {
+            // flat line when the price has not moved
+            let level = if max > min { ((p - min) / (max - min) * 7.0).round() as usize } else { 3 };
+            SPARK_BARS[level.min(7)]
+        }).collect();
+    // pad on the left so the latest sample is always at the right edge
+    format!("{}{}", " ".repeat(width - points.len()), line)
+}
+
+// format a time on the exchange clock, followed by the local time when they differ
+fn both_times(time: DateTime<FixedOffset>) -> String {
+    let local = time.with_timezone(&Local);
+    let exchange = time.with_timezone(&calendar::exchange_tz()).format("%H:%M:%S CST").to_string();
+    if local.offset().local_minus_utc() == calendar::exchange_tz().local_minus_utc() {
+        exchange
+    } else {
+        format!("{} ({} LOCAL)", exchange, local.format("%H:%M:%S"))
+    }
+}
+
 // TUI for stock detail
-pub fn stock_detail(app: &App) -> Paragraph {
+pub fn stock_detail(app: &App) -> Paragraph<'_> {
     let mut info = String::new();
-    let sel = app.stocks_state.selected().unwrap_or(0);
+    let sel = app.selected.unwrap_or(0);
     // prevent sel from exceeding the list range
     let stocks = app.stocks.lock().unwrap();
-    if app.stocks_state.selected().is_some() && sel < stocks.len() {
+    if app.selected.is_some() && sel < stocks.len() {
         let stock = stocks.get(sel).unwrap();
-        info = format!("CODE:{}\nUP_DOWN:{:+.2}%\nCURRENT:{}\nOPEN:{}\nYESTERDAY_CLOSE:{}\nHIGH:{}\nLOW:{}", 
-            stock.code, stock.percent * 100.0, stock.price, stock.open, stock.yestclose, stock.high, stock.low);
+        info = format!("CODE:{}\nUP_DOWN:{:+.2}%\nCURRENT:{}\nOPEN:{}\nYESTERDAY_CLOSE:{}\nHIGH:{}\nLOW:{}\nVOLUME:{}", 
+            stock.code, stock.percent * 100.0, stock.price, stock.open, stock.yestclose, stock.high, stock.low, stock.volume);
+        if let Some(time) = stock.time {
+            info += &format!("\nTIME:{}", both_times(time));
+        }
+        // the best level of the order book, when the feed sends one
+        if let (Some(bid), Some(ask)) = (stock.bids.first(), stock.asks.first()) {
+            info += &format!("\nBID:{} x {}\nASK:{} x {}", bid.0, bid.1, ask.0, ask.1);
+        }
+        if let (Some(quantity), Some(cost)) = (stock.quantity, stock.cost) {
+            info += &format!("\nHOLDING:{} @ {}\nPROFIT:{:+.2}", quantity, cost, (stock.price - cost) * quantity);
+        }
+        if stock.cached {
+            info += "\nCACHED, WAITING FOR LIVE DATA";
+        }
     }
 
     Paragraph::new(info)
@@ -101,33 +215,143 @@ pub fn stock_detail(app: &App) -> Paragraph {
             .border_type(BorderType::Plain))
 }
 
-pub fn stock_input(app: &App) -> Paragraph {
+pub fn stock_input(app: &App) -> Paragraph<'_> {
+    let title = match app.state {
+        AppState::Importing => "ENTER FILE TO IMPORT",
+        AppState::Exporting => "ENTER FILE TO EXPORT TO",
+        _ => "ENDER STOCK CODE",
+    };
     Paragraph::new(app.input.as_ref())
         .style(Style::default().fg(Color::Yellow))
-        .block(Block::default().borders(Borders::ALL).title("ENDER STOCK CODE"))
+        .block(Block::default().borders(Borders::ALL).title(title))
 }
 
-pub fn title_bar(app: &App, rect: Rect) -> Paragraph {
-    let left = format!("Stock v{}", VERSION);
+// TUI for the rows of an import waiting for confirmation
+pub fn import_preview(app: &App) -> Paragraph<'_> {
+    let (summary, rows) = match &app.preview {
+        Some(preview) => (preview.summary(), preview.rows.iter().map(|row| Spans::from(Span::styled(row.to_string(),
+            Style::default().fg(match row.outcome {
+                Outcome::Recognized => Color::White,
+                Outcome::Normalized(_) => Color::Yellow,
+                Outcome::Rejected(_) => Color::Red,
+            })))).collect()),
+        None => (String::new(), vec![]),
+    };
+    Paragraph::new(rows)
+        .wrap(Wrap { trim: false })
+        .block(Block::default().borders(Borders::ALL).title(format!("IMPORT PREVIEW: {}", summary)))
+}
+
+pub fn title_bar(app: &App, rect: Rect) -> Paragraph<'_> {
+    let now = app.now();
+    let phase = app.calendar.phase(now);
+    // show the session phase, and the countdown to the next open when not trading
+    let left = if phase.is_trading() {
+        format!("Stock v{} | {}", VERSION, phase.name())
+    } else {
+        format!("Stock v{} | {} | OPEN IN {}", VERSION, phase.name(), app.calendar.countdown(now))
+    };
+    // check before locking the error, the refresh job locks them in the other order
+    let refreshing = app.is_refreshing();
+    let retry = app.retry.lock().unwrap().clone();
     let error = app.error.lock().unwrap();
-    let right = if error.is_empty() { app.last_refresh.lock().unwrap().format("LAST UPDATE %H:%M:%S").to_string() } else { error.clone() };
+    // a retry in progress is more recent than the last error
+    let mut right = if !retry.is_empty() { retry.clone() }
+        else if error.is_empty() { format!("LAST UPDATE {}", both_times(*app.last_refresh.lock().unwrap())) }
+        else { error.clone() };
+    if refreshing {
+        right = format!("{} {}", SPINNER[(app.tick_count % SPINNER.len() as u128) as usize], right);
+    }
     Paragraph::new(Spans::from(vec![
         Span::raw(left.clone()),
         // Use checked_sub to prevent overflow
-        Span::raw(" ".repeat((rect.width as usize).checked_sub(right.width() + left.width()).unwrap_or(0))),
+        Span::raw(" ".repeat((rect.width as usize).saturating_sub(right.width() + left.width()))),
         Span::styled(right,Style::default()
-            .fg(if error.is_empty() { Color::White } else { Color::Red })),
+            .fg(if !retry.is_empty() { Color::Yellow } else if error.is_empty() { Color::White } else { Color::Red })),
         ]))
     .alignment(Alignment::Left)
 }
 
 // Status bar
-pub fn status_bar(app: &mut App) -> Paragraph {    
+pub fn status_bar(app: &mut App) -> Paragraph<'_> {    
+    // the result of the last action goes before the keys
+    if !app.notice.is_empty() {
+        return Paragraph::new(app.notice.clone()).style(Style::default().fg(Color::Yellow)).alignment(Alignment::Left);
+    }
     Paragraph::new(match app.state {
             // at Normal AppState when reading stocks
-            AppState::Normal => "EXIT[Q] | NEW[N] | DEL[D] | REFRESH[R] | UP[U] | DOWN[J]", 
+            AppState::Normal => "EXIT[Q] | NEW[N] | DEL[D] | REFRESH[R] | UP[U] | DOWN[J] | HEATMAP[H] | IMPORT[I] | EXPORT[E]", 
             // at Adding AppState when adding stocks
-            AppState::Adding => "ENTER[Enter] | CANCELL[ESC] | ADD 0 AHEAD OF SHANGHAI STOCK EXCHANGE CODE, 1 FOR SHENZHEN STOCK EXCHANGE"
+            AppState::Adding => "ENTER[Enter] | CANCELL[ESC] | ADD 0 AHEAD OF SHANGHAI STOCK EXCHANGE CODE, 1 FOR SHENZHEN STOCK EXCHANGE",
+            // at Importing and Exporting AppState when entering a file
+            AppState::Importing => "PREVIEW[Enter] | CANCELL[ESC] | <FILE> [--format csv|json|broker] [--map code=<column>,name=..,quantity=..,cost=..]",
+            AppState::Exporting => "EXPORT[Enter] | CANCELL[ESC] | <FILE> [--format csv|json|broker], .json FOR JSON, BROKER CSV OTHERWISE",
+            // at Previewing AppState before importing
+            AppState::Previewing => "IMPORT[Enter] | CANCELL[ESC]",
         }.to_string()
     ).alignment(Alignment::Left)
 }
+
+// draw the whole App into the frame
+// generic over the backend, so the screen can be rendered into a TestBackend
+// list_state keeps the rolling position of the list between frames
+pub fn draw<B: Backend>(frame: &mut Frame<B>, app: &mut App, list_state: &mut ListState) {
+    let chunks = main_chunks(frame.size());
+    
+    // need to tune render_stateful_widget when rendering the list
+    // otherwise the rolling status is incorrect
+    list_state.select(app.selected);
+    frame.render_stateful_widget(stock_list(&app.stocks.lock().unwrap(), app.stale_before()), chunks[1], list_state);
+
+    // the heatmap covers both the list and the detail
+    if let AppView::Heatmap = app.view {
+        let stocks = app.stocks.lock().unwrap();
+        frame.render_widget(Clear, chunks[5]);
+        for (i, tile) in heatmap_chunks(chunks[5], stocks.len()).into_iter().enumerate() {
+            frame.render_widget(heatmap_tile(&stocks[i], app.selected == Some(i)), tile);
+        }
+    }
+    
+    // Since rendering stock list would change the rolling status, 
+    // if this value is needed later, has to do the list rendering
+    frame.render_widget(title_bar(app, frame.size()), chunks[0]);
+    if let AppView::List = app.view {
+        frame.render_widget(stock_detail(app), chunks[2]);
+    }
+    frame.render_widget(status_bar(app), chunks[3]);
+
+    if let AppState::Previewing = app.state {
+        frame.render_widget(Clear, chunks[5]);
+        frame.render_widget(import_preview(app), chunks[5]);
+    }
+
+    if let AppState::Adding | AppState::Importing | AppState::Exporting = app.state {
+        // clear before popup, otherwise the background color would also popup
+        frame.render_widget(Clear, chunks[4]);
+        frame.render_widget(stock_input(app), chunks[4]);
+        
+        // display the cursor
+        // width() interface depends on an external lib
+        // can handle text width for many languages including Mandarin
+        frame.set_cursor(chunks[4].x + app.input.width() as u16 + 1, chunks[4].y + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparklines_scale_to_the_range_and_keep_the_latest_sample_on_the_right() {
+        assert_eq!(sparkline(&VecDeque::new(), 4), "    ");
+        assert_eq!(sparkline(&VecDeque::from([1.0, 2.0]), 0), "");
+        // fewer samples than width are padded on the left
+        assert_eq!(sparkline(&VecDeque::from([1.0, 8.0, 4.5]), 5), "  ▁█▅");
+        // a price that did not move is a flat line in the middle
+        assert_eq!(sparkline(&VecDeque::from([7.5, 7.5, 7.5]), 3), "▄▄▄");
+        // more samples than width show the last sample of each bucket
+        let samples: VecDeque<f64> = (1..=8).map(f64::from).collect();
+        assert_eq!(sparkline(&samples, 4), "▁▃▆█");
+        assert_eq!(sparkline(&samples, 4).chars().count(), 4);
+    }
+}
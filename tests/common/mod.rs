@@ -0,0 +1,85 @@
+// helpers shared by the tests driving an App, without a terminal or the network
+#![allow(dead_code)]
+use std::{collections::HashMap, ops::Deref, path::Path, sync::Arc, thread, time::{Duration, Instant}};
+
+use chrono::{DateTime, FixedOffset, TimeZone};
+use stock::{App, Config, calendar, net::{FetchError, HttpConfig}, provider::{Provider, Quote}, store::{self, Entry}};
+use tempfile::TempDir;
+
+// answers with fixed quotes, or with an error when there are none
+pub struct Stub(pub HashMap<String, Quote>);
+
+impl Provider for Stub {
+    fn name(&self) -> &str {
+        "stub"
+    }
+
+    fn fetch(&self, codes: &[String], _http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        if self.0.is_empty() {
+            return Err(FetchError::Server(String::from("FEED DOWN")));
+        }
+        Ok(codes.iter().filter_map(|code| self.0.get(code).map(|quote| (code.clone(), quote.clone()))).collect())
+    }
+}
+
+pub fn quote(title: &str, price: f64, percent: f64) -> Quote {
+    Quote { title: title.to_string(), price, percent, open: price, yestclose: price, high: price, low: price, ..Quote::default() }
+}
+
+pub fn stub() -> Arc<Stub> {
+    Arc::new(Stub(HashMap::from([
+        (String::from("0600000"), quote("PUFA", 7.5, 0.01)),
+        (String::from("1000001"), quote("PINGAN", 11.2, -0.02)),
+        (String::from("0601318"), quote("INSURE", 45.0, 0.0)),
+    ])))
+}
+
+// a fixed time while trading, a wednesday morning, so the tests do not depend on the wall clock
+pub fn trading_time() -> DateTime<FixedOffset> {
+    calendar::exchange_tz().with_ymd_and_hms(2024, 7, 10, 10, 0, 0).unwrap()
+}
+
+// the files of an App in a temp dir removed on drop, on a clock stopped at trading_time
+// derefs to the data file
+pub struct Scratch {
+    pub config: Config,
+    _dir: TempDir,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { clock: calendar::fixed_clock(trading_time()), ..Config::under(dir.path()) };
+        Self { config, _dir: dir }
+    }
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.config.db_path
+    }
+}
+
+// an App on a fresh data file holding the codes, in a scratch dir
+pub fn app_with(codes: &[&str], provider: Arc<dyn Provider>) -> (App, Scratch) {
+    let scratch = Scratch::new();
+    let entries: Vec<Entry> = codes.iter().map(|code| Entry::new(code)).collect();
+    store::save(&scratch, &entries).unwrap();
+    let mut app = App::try_with(scratch.config.clone(), provider).unwrap();
+    wait(&app);
+    // the first refresh runs while the data file was just written, settle that before driving the App
+    app.tick();
+    wait(&app);
+    (app, scratch)
+}
+
+// wait for the refresh in flight to finish
+pub fn wait(app: &App) {
+    let start = Instant::now();
+    while app.is_refreshing() {
+        assert!(start.elapsed() < Duration::from_secs(5), "refresh did not finish");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
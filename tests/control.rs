@@ -0,0 +1,81 @@
+// drive the App through the control socket, like a script talking to a running TUI
+#![cfg(all(unix, feature = "tui"))]
+mod common;
+
+use std::{io::{BufRead, BufReader, Write}, os::unix::net::UnixStream, thread, time::{Duration, Instant}};
+
+use common::{app_with, stub, wait};
+use serde_json::{Value, json};
+use stock::{events, control::{self, Command}, store};
+
+#[test]
+fn commands_go_through_the_key_handlers() {
+    let (mut app, path) = app_with(&["0600000"], stub());
+    let reply = events::on_command(Command::Add { code: String::from("sz000001") }, &mut app);
+    assert_eq!(reply, json!({"ok": true, "code": "1000001"}));
+    wait(&app);
+    assert_eq!(store::load(&path).unwrap().len(), 2);
+    assert_eq!(events::on_command(Command::Add { code: String::from("1000001") }, &mut app)["ok"], false);
+    assert_eq!(events::on_command(Command::Add { code: String::from("nope") }, &mut app)["ok"], false);
+
+    // the codes of select and remove are normalized like those of add
+    let reply = events::on_command(Command::Select { code: String::from("000001.SZ") }, &mut app);
+    assert_eq!(reply, json!({"ok": true, "code": "1000001"}));
+    assert_eq!(app.selected, Some(1));
+    let reply = events::on_command(Command::Quotes, &mut app);
+    let quotes = reply["quotes"].as_array().unwrap();
+    assert_eq!((&quotes[1]["code"], &quotes[1]["title"]), (&json!("1000001"), &json!("PINGAN")));
+
+    assert_eq!(events::on_command(Command::Select { code: String::from("nope") }, &mut app)["ok"], false);
+    events::on_command(Command::Remove { code: String::from("sh600000") }, &mut app);
+    assert_eq!(app.selected, None);
+    assert_eq!(store::load(&path).unwrap()[0].code, "1000001");
+    assert_eq!(events::on_command(Command::Remove { code: String::from("0600000") }, &mut app)["ok"], false);
+}
+
+#[test]
+fn the_watchlist_can_be_switched() {
+    let (mut app, path) = app_with(&["0600000"], stub());
+    let (_, other) = app_with(&["1000001", "0601318"], stub());
+    let reply = events::on_command(Command::Watchlist { path: other.display().to_string() }, &mut app);
+    assert_eq!(reply["codes"], 2);
+    wait(&app);
+    assert_eq!(app.stocks.lock().unwrap()[0].code, "1000001");
+    // a file that cannot be loaded keeps the current watchlist
+    let missing = path.with_file_name("missing").join("stocks.json");
+    assert_eq!(events::on_command(Command::Watchlist { path: missing.display().to_string() }, &mut app)["ok"], false);
+    assert_eq!(app.stocks.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn lines_on_the_socket_get_replies() {
+    let (mut app, scratch) = app_with(&["0600000"], stub());
+    let path = scratch.with_file_name("stocks.sock");
+    let control = control::listen(&path).unwrap();
+    // a second instance cannot take over the socket
+    assert!(control::listen(&path).is_err());
+
+    let stream = UnixStream::connect(&path).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut ask = |line: &str| -> Value {
+        writeln!(writer, "{}", line).unwrap();
+        let start = Instant::now();
+        while control.run(&mut app) == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5), "command not received");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        serde_json::from_str(&reply).unwrap()
+    };
+    assert_eq!(ask(r#"{"cmd":"select","code":"0600000"}"#), json!({"ok": true, "code": "0600000"}));
+    assert_eq!(ask(r#"{"cmd":"quotes"}"#)["quotes"][0]["price"], 7.5);
+    assert_eq!(ask("not json")["ok"], false);
+    assert_eq!(app.selected, Some(0));
+
+    // the socket file goes with the listener
+    drop(control);
+    assert!(!path.exists());
+}
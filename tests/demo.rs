@@ -0,0 +1,62 @@
+// check the synthetic quotes of the demo provider and the pace of an App on them
+mod common;
+
+use std::sync::Arc;
+
+use common::{app_with, wait};
+use stock::{calendar::Calendar, demo::{Demo, DEMO_REFRESH_SECS}, net::HttpConfig, provider::Provider};
+
+#[test]
+fn each_code_starts_from_its_own_day() {
+    let codes = vec![String::from("0600000"), String::from("1000001")];
+    let first = Demo::new(60.0).fetch(&codes, &HttpConfig::default()).unwrap();
+    let again = Demo::new(60.0).fetch(&codes, &HttpConfig::default()).unwrap();
+    for code in codes.iter() {
+        assert_eq!((first[code].yestclose, first[code].open), (again[code].yestclose, again[code].open));
+    }
+    assert_ne!(first["0600000"].yestclose, first["1000001"].yestclose);
+    assert_eq!(first["0600000"].title, "DEMO600000");
+}
+
+#[test]
+fn quotes_stay_plausible() {
+    // a trading day in a few milliseconds
+    let demo = Demo::new(1e7);
+    let codes = vec![String::from("0601318")];
+    let (mut yestclose, mut days) = (0.0, 0);
+    for _ in 0..200 {
+        let quote = demo.fetch(&codes, &HttpConfig::default()).unwrap().remove("0601318").unwrap();
+        // the days roll over, each starting from the close of the last one (the unit tests of demo check the rollover itself)
+        if quote.yestclose != yestclose {
+            (yestclose, days) = (quote.yestclose, days + 1);
+        }
+        assert!(quote.low <= quote.price && quote.price <= quote.high, "{:?}", quote);
+        assert!(quote.low <= quote.open && quote.open <= quote.high);
+        // within the limits, which are rounded to 0.01
+        assert!((quote.price - quote.yestclose).abs() <= quote.yestclose * 0.1 + 0.005, "{:?}", quote);
+        assert!((quote.percent - (quote.price - quote.yestclose) / quote.yestclose).abs() < 1e-9);
+        assert!(quote.volume > 0.0);
+        // stamped in the sessions of the simulated days
+        assert!(Calendar::default().phase(quote.time.unwrap()).is_trading(), "{:?}", quote.time);
+        assert_eq!((quote.bids.len(), quote.asks.len()), (5, 5));
+        assert_eq!(quote.bids[0].0, quote.price);
+        assert!(quote.bids.windows(2).all(|w| w[0].0 > w[1].0));
+        assert!(quote.asks.windows(2).all(|w| w[0].0 < w[1].0) && quote.asks[0].0 > quote.price);
+        std::thread::sleep(std::time::Duration::from_micros(200));
+    }
+    // 200 steps of at least 2000 simulated seconds each are about 28 days of 4 hours
+    assert!(days > 10, "{} days", days);
+}
+
+#[test]
+fn an_app_on_the_demo_refreshes_at_any_hour() {
+    let (mut app, _path) = app_with(&["0600000", "1000001"], Arc::new(Demo::new(60.0)));
+    {
+        let stocks = app.stocks.lock().unwrap();
+        assert!(stocks.iter().all(|stock| stock.price != 0.0 && !stock.bids.is_empty()));
+        assert_eq!(stocks[1].title, "DEMO000001");
+    }
+    let due: Vec<bool> = (0..DEMO_REFRESH_SECS * 2).map(|_| app.tick()).collect();
+    assert_eq!(due.iter().filter(|due| **due).count(), 2);
+    wait(&app);
+}
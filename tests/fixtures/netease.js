@@ -0,0 +1 @@
+_ntes_quote_callback({"0600000":{"code":"0600000","name":"浦发银行","price":7.5,"percent":0.010101,"open":7.43,"yestclose":7.425,"high":7.52,"low":7.4,"volume":12345600,"time":"2023/07/14 15:00:03","bid1":7.5,"bidvol1":120000,"bid2":7.49,"bidvol2":30000,"ask1":7.51,"askvol1":80000,"ask2":7.52,"askvol2":20000}});
\ No newline at end of file
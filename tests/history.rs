@@ -0,0 +1,55 @@
+// record the refreshes of an App on a movable clock and check when a day is compacted into a bar
+mod common;
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use chrono::{DateTime, FixedOffset, TimeZone};
+use common::{quote, Scratch, Stub};
+use stock::{App, Config, calendar, history, store::{self, Entry}};
+
+fn at(hour: u32, min: u32, sec: u32) -> DateTime<FixedOffset> {
+    calendar::exchange_tz().with_ymd_and_hms(2024, 7, 10, hour, min, sec).unwrap()
+}
+
+// wait for the compaction in the background to write the bars of the code
+fn bars(config: &Config, code: &str) -> Vec<history::Bar> {
+    let start = Instant::now();
+    loop {
+        let bars = history::daily(&config.history_dir, code);
+        if !bars.is_empty() || start.elapsed() > Duration::from_secs(2) {
+            return bars;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn a_day_is_compacted_after_its_closing_quotes() {
+    let scratch = Scratch::new();
+    let now = Arc::new(Mutex::new(at(14, 59, 0)));
+    let clock = now.clone();
+    let config = Config { clock: Arc::new(move || *clock.lock().unwrap()), ..scratch.config.clone() };
+    let mut closing = quote("PUFA", 7.6, 0.01);
+    closing.time = Some(at(15, 0, 3));
+    store::save(&scratch, &[Entry::new("0600000")]).unwrap();
+    let mut app = App::try_with(config.clone(), Arc::new(Stub(HashMap::from([(String::from("0600000"), closing)])))).unwrap();
+    common::wait(&app);
+
+    // at the close the last refresh may not have the closing quotes yet, so nothing is compacted
+    *now.lock().unwrap() = at(15, 0, 0);
+    app.tick();
+    thread::sleep(Duration::from_millis(100));
+    assert!(history::daily(&config.history_dir, "0600000").is_empty());
+
+    // the auto refresh goes on after the close until one succeeds, then the day is compacted
+    *now.lock().unwrap() = at(15, 1, 30);
+    assert!((0..60).any(|_| app.tick()));
+    app.refresh_stocks();
+    common::wait(&app);
+    app.tick();
+    let bars = bars(&config, "0600000");
+    assert_eq!(bars.len(), 1);
+    assert_eq!((bars[0].date, bars[0].close), (at(0, 0, 0).date_naive(), 7.6));
+    // and the auto refresh stops for the night
+    assert!(!(0..120).any(|_| app.tick()));
+}
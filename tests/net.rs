@@ -0,0 +1,113 @@
+// exercise the quote fetcher against local stand-in servers
+use std::{io::{Read, Write}, net::TcpListener, sync::mpsc::channel, thread, time::Duration};
+
+use stock::net::{self, FetchError, HttpConfig};
+
+// serve one connection with the given response, sending back the request it received
+fn serve_once(response: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        sender.send(String::from_utf8_lossy(&request).to_string()).unwrap();
+        stream.write_all(response.as_bytes()).unwrap();
+    });
+    (addr, receiver)
+}
+
+const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello";
+
+#[test]
+fn get_sends_user_agent() {
+    let (addr, request) = serve_once(OK);
+    let config = HttpConfig { user_agent: String::from("stock-test"), ..HttpConfig::default() };
+    let body = net::get(&format!("http://{}/feed", addr), &config).unwrap();
+    assert_eq!(body, b"hello");
+    let request = request.recv().unwrap();
+    assert!(request.starts_with("GET /feed HTTP/1.1\r\n"));
+    assert!(request.contains("User-Agent: stock-test\r\n"));
+}
+
+#[test]
+fn get_with_sends_extra_headers() {
+    let (addr, request) = serve_once(OK);
+    net::get_with(&format!("http://{}/list=sh600000", addr), &[("Referer", "https://finance.sina.com.cn/")], &HttpConfig::default()).unwrap();
+    assert!(request.recv().unwrap().contains("Referer: https://finance.sina.com.cn/\r\n"));
+    // through a proxy too
+    let (proxy, request) = serve_once(OK);
+    let config = HttpConfig { http_proxy: Some(proxy), ..HttpConfig::default() };
+    net::get_with("http://hq.example.com/list=sh600000", &[("Referer", "https://finance.sina.com.cn/")], &config).unwrap();
+    assert!(request.recv().unwrap().contains("Referer: https://finance.sina.com.cn/\r\n"));
+}
+
+#[test]
+fn get_reports_server_errors_as_retryable() {
+    let (addr, _request) = serve_once("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    let err = net::get(&format!("http://{}/feed", addr), &HttpConfig::default()).unwrap_err();
+    assert!(matches!(err, FetchError::Status(503)));
+    assert!(err.is_retryable());
+}
+
+#[test]
+fn get_times_out_on_a_hung_server() {
+    // accept the connection but never answer
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hung = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(2));
+        drop(stream);
+    });
+    let config = HttpConfig { read_timeout: Duration::from_millis(200), ..HttpConfig::default() };
+    let err = net::get(&format!("http://{}/feed", addr), &config).unwrap_err();
+    assert!(matches!(err, FetchError::Network(_)));
+    hung.join().unwrap();
+}
+
+#[test]
+fn get_goes_through_the_http_proxy() {
+    let (proxy, request) = serve_once(OK);
+    let config = HttpConfig { http_proxy: Some(proxy), ..HttpConfig::default() };
+    let body = net::get("http://quotes.example.com/feed/0600000", &config).unwrap();
+    assert_eq!(body, b"hello");
+    // the proxy gets the full url in the request line
+    let request = request.recv().unwrap();
+    assert!(request.starts_with("GET http://quotes.example.com/feed/0600000 HTTP/1.0\r\n"));
+    assert!(request.contains("Host: quotes.example.com\r\n"));
+}
+
+#[test]
+fn get_tunnels_https_through_the_proxy() {
+    // refuse the tunnel, the request must not be sent in plain text
+    let (proxy, request) = serve_once("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+    let config = HttpConfig { https_proxy: Some(format!("http://{}", proxy)), ..HttpConfig::default() };
+    let err = net::get("https://quotes.example.com/feed/0600000", &config).unwrap_err();
+    assert!(matches!(err, FetchError::Status(403)));
+    let request = request.recv().unwrap();
+    assert!(request.starts_with("CONNECT quotes.example.com:443 HTTP/1.1\r\n"));
+}
+
+#[test]
+fn proxies_come_from_the_environment() {
+    let config = HttpConfig::from_vars(|name| match name {
+        "HTTP_PROXY" => Some(String::from("http://proxy.corp:3128")),
+        "https_proxy" => Some(String::from("http://secure.corp:3128")),
+        "NO_PROXY" => Some(String::from("localhost, .internal.corp")),
+        _ => None,
+    });
+    assert_eq!(config.proxy_for("http", "api.money.126.net"), Some("http://proxy.corp:3128"));
+    assert_eq!(config.proxy_for("https", "api.money.126.net"), Some("http://secure.corp:3128"));
+    assert_eq!(config.proxy_for("http", "localhost"), None);
+    assert_eq!(config.proxy_for("http", "quotes.internal.corp"), None);
+    assert_eq!(config.proxy_for("http", "internal.corp"), None);
+}
@@ -0,0 +1,180 @@
+// parse hand-written payloads in the formats and encodings of the quote feeds, and fail over between feeds
+mod common;
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use common::{quote, Stub};
+use stock::{net::{FetchError, HttpConfig, Limiters, RetryPolicy}, provider::{self, Failover, NetEase, Provider, Quote, Sina, Tencent}};
+
+fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+}
+
+// the quotes of 0600000 and 1000001 are the same in every fixture, up to what each feed sends
+fn check_pufa(quote: &Quote) {
+    assert_eq!(quote.title, "浦发银行");
+    assert_eq!((quote.price, quote.open, quote.yestclose, quote.high, quote.low), (7.5, 7.43, 7.425, 7.52, 7.4));
+    assert!((quote.percent - 0.0101).abs() < 1e-4, "{}", quote.percent);
+    assert_eq!(quote.volume, 12345600.0);
+    assert_eq!(quote.time.unwrap().to_rfc3339(), "2023-07-14T15:00:03+08:00");
+    assert_eq!((quote.bids[0], quote.bids[1]), ((7.5, 120000.0), (7.49, 30000.0)));
+    assert_eq!((quote.asks[0], quote.asks[1]), ((7.51, 80000.0), (7.52, 20000.0)));
+}
+
+#[test]
+fn netease_quotes_are_parsed() {
+    let quotes = NetEase::parse(&String::from_utf8(fixture("netease.js")).unwrap()).unwrap();
+    check_pufa(&quotes["0600000"]);
+    assert!(NetEase::parse("<html>busy</html>").is_err());
+}
+
+#[test]
+fn sina_quotes_are_parsed() {
+    let quotes = Sina::parse(&fixture("sina.txt")).unwrap();
+    // the unknown code, answered with an empty quote, is left out
+    assert_eq!(quotes.len(), 2);
+    assert!(!quotes.contains_key("0999999"));
+    check_pufa(&quotes["0600000"]);
+    assert_eq!(quotes["0600000"].bids.len(), 5);
+    let pingan = &quotes["1000001"];
+    assert_eq!((pingan.title.as_str(), pingan.price, pingan.volume), ("平安银行", 11.2, 98765400.0));
+    assert!((pingan.percent + 0.0201).abs() < 1e-4);
+    assert!(Sina::parse(b"Kinsoku jikou desu!").is_err());
+}
+
+#[test]
+fn tencent_quotes_are_parsed() {
+    let quotes = Tencent::parse(&fixture("tencent.txt")).unwrap();
+    // v_pv_none_match, the answer for unknown codes, is not a quote
+    assert_eq!(quotes.len(), 2);
+    assert!(quotes.keys().all(|code| code == "0600000" || code == "1000001"));
+    check_pufa(&quotes["0600000"]);
+    assert_eq!(quotes["0600000"].asks[4], (7.55, 500.0));
+    let pingan = &quotes["1000001"];
+    assert_eq!((pingan.title.as_str(), pingan.price, pingan.volume), ("平安银行", 11.2, 98765400.0));
+    assert!((pingan.percent + 0.0201).abs() < 1e-9);
+    assert!(Tencent::parse(b"<html>busy</html>").is_err());
+}
+
+#[test]
+fn bad_payloads_are_errors_or_skipped() {
+    // cut off, or with values of the wrong type
+    assert!(matches!(NetEase::parse(r#"_ntes_quote_callback({"0600000":{"price":7.5,"na);"#), Err(FetchError::Server(_))));
+    let quotes = NetEase::parse(r#"_ntes_quote_callback({"0600000":{"price":"7.5","name":1},"1000001":[]});"#).unwrap();
+    assert_eq!((quotes["0600000"].title.as_str(), quotes["0600000"].price), ("0600000", 0.0));
+    assert!(!quotes.contains_key("1000001"));
+    // lines too short for a quote, and codes that are not codes
+    let quotes = Sina::parse(b"var hq_str_sh600000=\"PUFA,7.43,7.425\";\nvar hq_str_xx=\"\";\nvar hq_str_sh600001=\"").unwrap();
+    assert!(quotes.is_empty());
+    let quotes = Tencent::parse(b"v_sh600000=\"1~PUFA~600000~abc\";\nv_pv_none_match=\"1\";").unwrap();
+    assert!(quotes.is_empty());
+}
+
+// answers with a payload cut off in the middle
+struct Truncated;
+
+impl Provider for Truncated {
+    fn name(&self) -> &str {
+        "truncated"
+    }
+
+    fn fetch(&self, _codes: &[String], _http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        NetEase::parse(r#"_ntes_quote_callback({"0600000":{"pri"#)
+    }
+}
+
+#[test]
+fn a_bad_payload_fails_the_refresh_without_breaking_the_app() {
+    let (mut app, _path) = common::app_with(&["0600000"], Arc::new(Truncated));
+    assert!(!app.is_refreshing());
+    assert_eq!(*app.error.lock().unwrap(), "BATCH 1/1: Server Returns Errors");
+    // the next refresh runs as usual
+    app.refresh_stocks();
+    common::wait(&app);
+    assert_eq!(app.stocks.lock().unwrap()[0].price, 0.0);
+    assert_eq!(app.metrics.lock().unwrap().failures["server"], 2);
+}
+
+#[test]
+fn codes_get_their_market_prefix() {
+    assert_eq!(provider::market_code("0600000"), "sh600000");
+    assert_eq!(provider::market_code("1000001"), "sz000001");
+    assert!(provider::from_names("tencent, sina").is_ok());
+    assert_eq!(provider::from_names("sina").unwrap().name(), "sina");
+    assert!(provider::from_names("yahoo").is_err());
+    assert!(provider::from_names("").is_err());
+}
+
+// counts its fetches
+struct Counting(Stub, &'static str, Mutex<usize>);
+
+impl Provider for Counting {
+    fn name(&self) -> &str {
+        self.1
+    }
+
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        *self.2.lock().unwrap() += 1;
+        self.0.fetch(codes, http)
+    }
+}
+
+#[test]
+fn failover_moves_to_the_next_provider_and_stays_there() {
+    let down = Arc::new(Counting(Stub(HashMap::new()), "down", Mutex::new(0)));
+    let up = Arc::new(Counting(Stub(HashMap::from([(String::from("0600000"), quote("PUFA", 7.5, 0.01))])), "up", Mutex::new(0)));
+    let failover = Failover::new(vec![down.clone(), up.clone()]);
+    let codes = vec![String::from("0600000")];
+    assert_eq!(failover.name(), "down");
+    assert_eq!(failover.fetch(&codes, &HttpConfig::default()).unwrap()["0600000"].price, 7.5);
+    assert_eq!(failover.name(), "up");
+    failover.fetch(&codes, &HttpConfig::default()).unwrap();
+    assert_eq!((*down.2.lock().unwrap(), *up.2.lock().unwrap()), (1, 2));
+
+    // the error of the last provider tried when all fail
+    let failover = Failover::new(vec![down.clone(), down.clone()]);
+    assert!(matches!(failover.fetch(&codes, &HttpConfig::default()), Err(FetchError::Server(_))));
+}
+
+#[test]
+fn failover_goes_back_to_the_first_provider_after_the_cooldown() {
+    let flaky = Arc::new(Counting(Stub(HashMap::new()), "flaky", Mutex::new(0)));
+    let up = Arc::new(Counting(Stub(HashMap::from([(String::from("0600000"), quote("PUFA", 7.5, 0.01))])), "up", Mutex::new(0)));
+    let mut failover = Failover::new(vec![flaky.clone(), up.clone()]);
+    failover.cooldown = Duration::ZERO;
+    let codes = vec![String::from("0600000")];
+    failover.fetch(&codes, &HttpConfig::default()).unwrap();
+    failover.fetch(&codes, &HttpConfig::default()).unwrap();
+    // the first provider is asked again each time the cooldown has passed
+    assert_eq!((*flaky.2.lock().unwrap(), *up.2.lock().unwrap()), (2, 2));
+}
+
+// fails like a feed that cannot be reached, which is worth retrying, and counts its fetches
+struct Unreachable(&'static str, Mutex<usize>);
+
+impl Provider for Unreachable {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn fetch(&self, _codes: &[String], _http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        *self.1.lock().unwrap() += 1;
+        Err(FetchError::Network(String::from("timed out")))
+    }
+}
+
+#[test]
+fn failover_retries_only_the_last_provider() {
+    let down = Arc::new(Unreachable("down", Mutex::new(0)));
+    let also_down = Arc::new(Unreachable("also-down", Mutex::new(0)));
+    let failover = Failover::new(vec![down.clone(), also_down.clone()]);
+    let policy = RetryPolicy { attempts: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) };
+    let limiters = Limiters::new(10, 10.0);
+    let retries = Mutex::new(Vec::new());
+    let ret = failover.fetch_with_retry(&[String::from("0600000")], &HttpConfig::default(), &policy, &limiters,
+        &|state| retries.lock().unwrap().push(state));
+    assert!(matches!(ret, Err(FetchError::Network(_))));
+    // a feed that is down costs one try before the next one is asked
+    assert_eq!((*down.1.lock().unwrap(), *also_down.1.lock().unwrap()), (1, 3));
+    assert_eq!(retries.lock().unwrap().len(), 2);
+}
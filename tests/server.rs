@@ -0,0 +1,264 @@
+// exercise the JSON API of the headless server with a local client
+#![cfg(feature = "serve")]
+mod common;
+
+use std::{collections::HashMap, io::{BufRead, BufReader, Read, Write}, net::{SocketAddr, TcpStream}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use common::{app_with, stub, wait, Stub};
+use serde_json::{Value, json};
+use stock::{App, server::{Hub, Server}, store};
+
+// serve the App on a free port
+fn serve(app: App) -> (SocketAddr, Arc<Mutex<App>>) {
+    let (addr, app, _hub) = serve_with_hub(app);
+    (addr, app)
+}
+
+fn serve_with_hub(app: App) -> (SocketAddr, Arc<Mutex<App>>, Arc<Hub>) {
+    let app = Arc::new(Mutex::new(app));
+    let server = Server::bind(app.clone(), "127.0.0.1:0").unwrap();
+    let addr = server.addr().unwrap();
+    let hub = server.hub();
+    thread::spawn(move || server.run());
+    (addr, app, hub)
+}
+
+// open a stream, returning the reader positioned after the head
+fn stream(addr: SocketAddr, path: &str) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("HTTP/1.1 200"), "{}", line);
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        if line.starts_with("Content-Type") {
+            assert!(line.contains("text/event-stream"));
+        }
+    }
+    reader
+}
+
+// read the next event, skipping comments
+fn next_event(reader: &mut BufReader<TcpStream>) -> (String, Value) {
+    let (mut event, mut data) = (String::new(), String::new());
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if let Some(name) = line.strip_prefix("event: ") {
+            event = name.to_string();
+        } else if let Some(json) = line.strip_prefix("data: ") {
+            data = json.to_string();
+        } else if line.is_empty() && !event.is_empty() {
+            return (event, serde_json::from_str(&data).unwrap());
+        }
+    }
+}
+
+// wait for the hub to register the stream of a client
+fn wait_for_streams(hub: &Hub, count: usize) {
+    let start = Instant::now();
+    while hub.len() < count {
+        assert!(start.elapsed() < Duration::from_secs(5), "stream not registered");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// send a request and return the status and the body
+fn request_text(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.0\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.split(' ').nth(1).unwrap().parse().unwrap(), body.to_string())
+}
+
+// send a request and return the status and the json body
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+    let (status, body) = request_text(addr, method, path, body);
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+#[test]
+fn quotes_are_served_as_json() {
+    let (app, _path) = app_with(&["0600000", "1000001"], stub());
+    let (addr, _app) = serve(app);
+    let (status, body) = request(addr, "GET", "/quotes", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["error"], "");
+    let quotes = body["quotes"].as_array().unwrap();
+    assert_eq!(quotes.len(), 2);
+    assert_eq!(quotes[0]["code"], "0600000");
+    assert_eq!(quotes[0]["title"], "PUFA");
+    assert_eq!(quotes[0]["price"], 7.5);
+    assert_eq!(quotes[1]["percent"], -0.02);
+
+    let (status, body) = request(addr, "GET", "/quotes/1000001", "");
+    assert_eq!((status, &body["title"]), (200, &json!("PINGAN")));
+    let (status, _) = request(addr, "GET", "/quotes/0601318", "");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn reads_do_not_wait_for_the_app() {
+    let (app, _path) = app_with(&["0600000"], stub());
+    let (addr, app) = serve(app);
+    // a change of the watchlist waits for the App, e.g. behind a tick, while reads are still answered
+    let locked = app.lock().unwrap();
+    let change = thread::spawn(move || request(addr, "DELETE", "/watchlist/0600000", ""));
+    thread::sleep(Duration::from_millis(100));
+    for path in ["/quotes", "/quotes/0600000", "/watchlist"] {
+        assert_eq!(request(addr, "GET", path, "").0, 200, "{}", path);
+    }
+    assert_eq!(request_text(addr, "GET", "/metrics", "").0, 200);
+    assert!(!change.is_finished());
+    drop(locked);
+    assert_eq!(change.join().unwrap().0, 200);
+    assert_eq!(request(addr, "GET", "/watchlist", "").1, json!([]));
+}
+
+#[test]
+fn the_watchlist_can_be_changed() {
+    let (app, path) = app_with(&["0600000"], stub());
+    let (addr, app) = serve(app);
+    // codes are normalized like on import
+    let (status, body) = request(addr, "POST", "/watchlist", r#"{"code":"sz000001","quantity":200,"cost":10.5}"#);
+    assert_eq!(status, 201);
+    assert_eq!(body["code"], "1000001");
+    wait(&app.lock().unwrap());
+    let (_, body) = request(addr, "GET", "/quotes/1000001", "");
+    assert_eq!(body["title"], "PINGAN");
+    assert_eq!(body["quantity"], 200.0);
+    // posting a code again updates its holding
+    let (status, _) = request(addr, "POST", "/watchlist", r#"{"code":"1000001","quantity":300}"#);
+    assert_eq!(status, 200);
+    let (status, body) = request(addr, "DELETE", "/watchlist/0600000", "");
+    assert_eq!((status, &body["code"]), (200, &json!("0600000")));
+
+    let (_, body) = request(addr, "GET", "/watchlist", "");
+    assert_eq!(body, json!([{"code": "1000001", "quantity": 300.0}]));
+    let saved = store::load(&path).unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].quantity, Some(300.0));
+}
+
+#[test]
+fn codes_are_deleted_in_any_format() {
+    let (app, path) = app_with(&["0600000", "1000001"], stub());
+    let (addr, _app) = serve(app);
+    let (status, body) = request(addr, "DELETE", "/watchlist/600000.SH", "");
+    assert_eq!((status, &body["code"]), (200, &json!("0600000")));
+    assert_eq!(request(addr, "DELETE", "/watchlist/sz000001", "").1["code"], "1000001");
+    assert!(store::load(&path).unwrap().is_empty());
+}
+
+#[test]
+fn bad_requests_are_rejected() {
+    let (app, _path) = app_with(&["0600000"], stub());
+    let (addr, _app) = serve(app);
+    assert_eq!(request(addr, "POST", "/watchlist", r#"{"code":"nope"}"#).0, 400);
+    assert_eq!(request(addr, "POST", "/watchlist", "not json").0, 400);
+    assert_eq!(request(addr, "DELETE", "/watchlist/1000001", "").0, 404);
+    assert_eq!(request(addr, "DELETE", "/watchlist/nope", "").0, 400);
+    assert_eq!(request(addr, "PUT", "/watchlist", "").0, 405);
+    assert_eq!(request(addr, "GET", "/nowhere", "").0, 404);
+}
+
+#[test]
+fn metrics_report_quotes_and_fetches() {
+    let (app, _path) = app_with(&["0600000", "1000001"], stub());
+    let (addr, _app) = serve(app);
+    let (status, text) = request_text(addr, "GET", "/metrics", "");
+    assert_eq!(status, 200);
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.contains(&r#"stock_price{code="0600000",title="PUFA"} 7.5"#), "{}", text);
+    assert!(lines.contains(&r#"stock_percent{code="1000001",title="PINGAN"} -0.02"#));
+    assert!(lines.contains(&"stock_fetch_successes_total 1"));
+    assert!(lines.contains(&r#"stock_fetch_failures_total{kind="server"} 0"#));
+    assert!(lines.contains(&r#"stock_refresh_duration_seconds_bucket{le="+Inf"} 1"#));
+    assert!(lines.contains(&"stock_refresh_duration_seconds_count 1"));
+    assert!(lines.iter().any(|line| line.starts_with("stock_last_success_timestamp_seconds ")));
+}
+
+#[test]
+fn metrics_count_failures_by_kind() {
+    let (app, _path) = app_with(&["1000002"], Arc::new(Stub(HashMap::new())));
+    let (addr, _app) = serve(app);
+    let (_, text) = request_text(addr, "GET", "/metrics", "");
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines.contains(&r#"stock_fetch_failures_total{kind="server"} 1"#), "{}", text);
+    assert!(lines.contains(&"stock_fetch_successes_total 0"));
+    // each test has its own scratch dir, so there is no cache to load, and this App has never fetched a quote: no prices and no success yet
+    assert!(!text.contains("stock_price{"));
+    assert!(!text.contains("stock_last_success_timestamp_seconds "));
+}
+
+#[test]
+fn streams_take_codes_in_any_format() {
+    let (app, _path) = app_with(&["0600000", "1000001"], stub());
+    let (addr, _app, hub) = serve_with_hub(app);
+    let mut some = stream(addr, "/stream?codes=sh600000,000001.SZ");
+    wait_for_streams(&hub, 1);
+    let (event, data) = next_event(&mut some);
+    assert_eq!(event, "snapshot");
+    let codes: Vec<&str> = data.as_array().unwrap().iter().map(|q| q["code"].as_str().unwrap()).collect();
+    assert_eq!(codes, vec!["0600000", "1000001"]);
+    // a code that is no code is refused instead of streaming nothing
+    let (status, body) = request(addr, "GET", "/stream?codes=0600000,nope", "");
+    assert_eq!(status, 400);
+    assert!(body["error"].as_str().unwrap().contains("nope"), "{}", body);
+    assert_eq!(hub.len(), 1);
+}
+
+#[test]
+fn streams_push_changes_of_the_subscribed_codes() {
+    let (app, _path) = app_with(&["0600000", "1000001", "0601318"], stub());
+    let (addr, app, hub) = serve_with_hub(app);
+    hub.publish(&app.lock().unwrap().stocks.lock().unwrap());
+    let mut all = stream(addr, "/stream");
+    let mut some = stream(addr, "/stream?codes=0600000,0601318");
+    wait_for_streams(&hub, 2);
+
+    let (event, data) = next_event(&mut all);
+    assert_eq!(event, "snapshot");
+    assert_eq!(data.as_array().unwrap().len(), 3);
+    let (_, data) = next_event(&mut some);
+    let codes: Vec<&str> = data.as_array().unwrap().iter().map(|q| q["code"].as_str().unwrap()).collect();
+    assert_eq!(codes, vec!["0600000", "0601318"]);
+
+    // only the changed fields are sent, and only to the subscribers of the code
+    {
+        let app = app.lock().unwrap();
+        let mut stocks = app.stocks.lock().unwrap();
+        stocks[1].update_price(11.3, 3);
+        stocks[2].update_price(45.5, 3);
+    }
+    hub.publish(&app.lock().unwrap().stocks.lock().unwrap());
+    assert_eq!(next_event(&mut all), (String::from("quote"), json!({"code": "1000001", "price": 11.3})));
+    assert_eq!(next_event(&mut all), (String::from("quote"), json!({"code": "0601318", "price": 45.5})));
+    assert_eq!(next_event(&mut some), (String::from("quote"), json!({"code": "0601318", "price": 45.5})));
+
+    app.lock().unwrap().remove_code("0600000").unwrap();
+    hub.publish(&app.lock().unwrap().stocks.lock().unwrap());
+    assert_eq!(next_event(&mut some), (String::from("removed"), json!({"code": "0600000"})));
+
+    // a client that went away is dropped at the next publish that writes to it
+    drop(all);
+    let start = Instant::now();
+    while hub.len() > 1 {
+        assert!(start.elapsed() < Duration::from_secs(5), "closed stream not dropped");
+        app.lock().unwrap().stocks.lock().unwrap()[0].update_price(changing_price(&start), 3);
+        hub.publish(&app.lock().unwrap().stocks.lock().unwrap());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// a price that changes on every call, so every publish sends an event
+fn changing_price(start: &Instant) -> f64 {
+    11.0 + start.elapsed().as_micros() as f64 / 1e9
+}
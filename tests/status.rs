@@ -0,0 +1,64 @@
+// fill status line templates and check where their quotes come from, without the network
+mod common;
+
+use std::{collections::HashMap, sync::Arc};
+
+use common::{quote, stub, Scratch, Stub};
+use stock::{Stock, status::{self, Template}};
+
+fn pufa() -> Stock {
+    let mut stock = Stock::new("0600000");
+    stock.title = String::from("PUFA");
+    stock.price = 7.5;
+    stock.yestclose = 7.425;
+    stock.percent = 0.0101;
+    stock.volume = 123456.0;
+    stock
+}
+
+fn render(template: &str, stock: &Stock, tmux: bool) -> String {
+    template.parse::<Template>().unwrap().render(stock, tmux)
+}
+
+#[test]
+fn templates_are_filled_with_the_quote() {
+    let stock = pufa();
+    assert_eq!(render(status::DEFAULT_FORMAT, &stock, false), "PUFA 7.50 +1.01%");
+    assert_eq!(render("{code}:{price:.3} {change:+} vol {volume}", &stock, false), "0600000:7.500 +0.08 vol 123456");
+    assert_eq!(render("[{title}]", &stock, true), "#[fg=red][PUFA]#[default]");
+    let mut falling = stock.clone();
+    falling.percent = -0.02;
+    assert_eq!(render("{percent:.1}%", &falling, true), "#[fg=green]-2.0%#[default]");
+    // unchanged quotes are not colored
+    assert_eq!(render("{title}", &Stock::new("0601318"), true), "0601318");
+}
+
+#[test]
+fn bad_templates_are_rejected() {
+    assert!("{name}".parse::<Template>().unwrap_err().contains("unknown field"));
+    assert!("{price:2}".parse::<Template>().unwrap_err().contains("invalid spec"));
+    assert!("{price".parse::<Template>().unwrap_err().contains("unclosed"));
+}
+
+#[test]
+fn fresh_quotes_come_from_the_cache() {
+    let scratch = Scratch::new();
+    let config = &scratch.config;
+    let codes = vec![String::from("0600000"), String::from("1000001")];
+    let template = status::DEFAULT_FORMAT.parse().unwrap();
+    assert_eq!(status::status_line(config, &codes, &template, false, 60, stub().as_ref()).unwrap(), "PUFA 7.50 +1.00% PINGAN 11.20 -2.00%");
+    // the feed is down, but the quotes were just cached
+    let down = Stub(HashMap::new());
+    assert_eq!(status::status_line(config, &codes, &template, false, 60, &down).unwrap(), "PUFA 7.50 +1.00% PINGAN 11.20 -2.00%");
+    // outdated quotes are fetched again
+    let moved = Stub(HashMap::from([(String::from("0600000"), quote("PUFA", 7.6, 0.02)), (String::from("1000001"), quote("PINGAN", 11.0, -0.03))]));
+    assert_eq!(status::status_line(config, &codes, &template, false, -1, &moved).unwrap(), "PUFA 7.60 +2.00% PINGAN 11.00 -3.00%");
+    // and kept from the cache when the feed fails
+    assert_eq!(status::status_line(config, &codes, &template, false, -1, &down).unwrap(), "PUFA 7.60 +2.00% PINGAN 11.00 -3.00%");
+
+    // a code without any quote fails when the feed does
+    let codes = vec![String::from("0601398")];
+    assert!(status::status_line(config, &codes, &template, false, 60, &Stub(HashMap::new())).is_err());
+    let stub = Arc::new(Stub(HashMap::from([(String::from("0601398"), quote("ICBC", 5.1, 0.0))])));
+    assert_eq!(status::status_line(config, &codes, &template, false, 60, stub.as_ref()).unwrap(), "ICBC 5.10 +0.00%");
+}
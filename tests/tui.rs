@@ -0,0 +1,283 @@
+// drive the App with synthetic events and check the rendered screen, without a terminal or the network
+#![cfg(feature = "tui")]
+mod common;
+
+use std::{collections::HashMap, fs, path::Path, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+
+use common::{app_with, stub, wait, Scratch, Stub};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+use stock::{App, AppState, Config, events, widget, store, net::{FetchError, HttpConfig}, provider::{Provider, Quote}, replay::{self, Recorder, Recording, Replay, ReplayClock, Step}};
+use tui::{Terminal, backend::TestBackend, widgets::ListState};
+
+fn key(app: &mut App, code: KeyCode) {
+    events::on_events(Event::Key(KeyEvent::new(code, KeyModifiers::NONE)), app);
+}
+
+fn click(app: &mut App, row: u16) {
+    events::on_events(Event::Mouse(MouseEvent {
+        kind: MouseEventKind::Up(MouseButton::Left), column: 5, row, modifiers: KeyModifiers::NONE,
+    }), app);
+}
+
+// render the App and return the screen as lines of text
+fn render(app: &mut App) -> Vec<String> {
+    let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+    terminal.draw(|f| widget::draw(f, app, &mut ListState::default())).unwrap();
+    let buffer = terminal.backend().buffer();
+    buffer.content.chunks(buffer.area.width as usize)
+        .map(|line| line.iter().map(|cell| cell.symbol.as_str()).collect())
+        .collect()
+}
+
+// open the input with the key and type the text, Enter is left to the test
+fn type_in(app: &mut App, open: char, text: &str) {
+    key(app, KeyCode::Char(open));
+    for c in text.chars() {
+        key(app, KeyCode::Char(c));
+    }
+}
+
+fn codes_in(path: &Path) -> Vec<String> {
+    store::load(path).unwrap().into_iter().map(|entry| entry.code).collect()
+}
+
+#[test]
+fn adding_a_stock_shows_its_quote_and_saves_it() {
+    let (mut app, path) = app_with(&[], stub());
+    key(&mut app, KeyCode::Char('n'));
+    for c in "0600000".chars() {
+        key(&mut app, KeyCode::Char(c));
+    }
+    assert!(render(&mut app).iter().any(|line| line.contains("ENDER STOCK CODE")));
+    key(&mut app, KeyCode::Enter);
+    wait(&app);
+    assert!(matches!(app.state, AppState::Normal));
+    assert_eq!(codes_in(&path), vec!["0600000"]);
+    let screen = render(&mut app);
+    assert!(screen[1..].iter().any(|line| line.contains("PUFA") && line.contains("+1.00%")), "{:#?}", screen);
+}
+
+#[test]
+fn importing_a_file_adds_its_stocks() {
+    let (mut app, path) = app_with(&["0600000"], stub());
+    let file = path.with_file_name("import.csv");
+    fs::write(&file, "code\nsz000001\n601318.SH\n").unwrap();
+    key(&mut app, KeyCode::Char('i'));
+    for c in file.to_string_lossy().chars() {
+        key(&mut app, KeyCode::Char(c));
+    }
+    key(&mut app, KeyCode::Enter);
+    assert!(matches!(app.state, AppState::Previewing));
+    assert!(render(&mut app).iter().any(|line| line.contains("0 RECOGNIZED, 2 NORMALIZED, 0 REJECTED")));
+    key(&mut app, KeyCode::Enter);
+    wait(&app);
+    assert_eq!(codes_in(&path), vec!["0600000", "1000001", "0601318"]);
+    assert!(render(&mut app).iter().any(|line| line.contains("IMPORTED 2 STOCKS")));
+}
+
+#[test]
+fn escape_cancels_adding() {
+    let (mut app, path) = app_with(&["0600000"], stub());
+    key(&mut app, KeyCode::Char('n'));
+    key(&mut app, KeyCode::Char('1'));
+    key(&mut app, KeyCode::Esc);
+    assert!(matches!(app.state, AppState::Normal));
+    assert_eq!(codes_in(&path), vec!["0600000"]);
+}
+
+#[test]
+fn keys_move_the_selection_and_reorder_stocks() {
+    let (mut app, path) = app_with(&["0600000", "1000001", "0601318"], stub());
+    // without a selection, the first row counts as selected
+    key(&mut app, KeyCode::Down);
+    assert_eq!(app.selected, Some(1));
+    assert!(render(&mut app).iter().any(|line| line.contains("CODE:1000001")));
+    // move the selected stock down, the selection follows it
+    key(&mut app, KeyCode::Char('j'));
+    assert_eq!(app.selected, Some(2));
+    assert_eq!(codes_in(&path), vec!["0600000", "0601318", "1000001"]);
+    key(&mut app, KeyCode::Char('u'));
+    key(&mut app, KeyCode::Char('u'));
+    assert_eq!(app.selected, Some(0));
+    assert_eq!(codes_in(&path), vec!["1000001", "0600000", "0601318"]);
+    // already at the top
+    key(&mut app, KeyCode::Up);
+    assert_eq!(app.selected, Some(0));
+}
+
+#[test]
+fn delete_removes_the_selected_stock() {
+    let (mut app, path) = app_with(&["0600000", "1000001"], stub());
+    key(&mut app, KeyCode::Down);
+    key(&mut app, KeyCode::Up);
+    key(&mut app, KeyCode::Char('d'));
+    assert_eq!(app.selected, None);
+    assert_eq!(codes_in(&path), vec!["1000001"]);
+    let screen = render(&mut app);
+    assert!(!screen.iter().any(|line| line.contains("PUFA")));
+    assert!(screen.iter().any(|line| line.contains("PINGAN")));
+    // nothing selected, so nothing more is deleted
+    key(&mut app, KeyCode::Char('d'));
+    assert_eq!(codes_in(&path), vec!["1000001"]);
+}
+
+#[test]
+fn clicking_a_row_selects_it() {
+    let (mut app, _path) = app_with(&["0600000", "1000001"], stub());
+    // the list starts on the third line, below the title bar and the border
+    click(&mut app, 3);
+    assert_eq!(app.selected, Some(1));
+    // rows below the list are ignored
+    click(&mut app, 10);
+    assert_eq!(app.selected, Some(1));
+    assert!(render(&mut app).iter().any(|line| line.contains("CODE:1000001")));
+}
+
+#[test]
+fn heatmap_shows_a_tile_per_stock() {
+    let (mut app, _path) = app_with(&["0600000", "1000001"], stub());
+    key(&mut app, KeyCode::Char('h'));
+    let screen = render(&mut app);
+    assert!(screen.iter().any(|line| line.contains("+1.00%")));
+    assert!(screen.iter().any(|line| line.contains("-2.00%")));
+    // the detail is hidden in the heatmap, and clicks do not select
+    click(&mut app, 2);
+    assert_eq!(app.selected, None);
+    key(&mut app, KeyCode::Char('h'));
+    assert!(render(&mut app).iter().any(|line| line.contains("DETAIL")));
+}
+
+#[test]
+fn fetch_errors_show_in_the_title_bar() {
+    let (mut app, _path) = app_with(&["0600000"], Arc::new(Stub(HashMap::new())));
+    assert!(render(&mut app)[0].contains("FEED DOWN"));
+}
+
+// panics on the first fetch, then answers like the stub
+struct PanicsOnce(AtomicBool);
+
+impl Provider for PanicsOnce {
+    fn name(&self) -> &str {
+        "panics once"
+    }
+
+    fn fetch(&self, codes: &[String], http: &HttpConfig) -> Result<HashMap<String, Quote>, FetchError> {
+        if !self.0.swap(true, Ordering::SeqCst) {
+            panic!("a bug in the provider");
+        }
+        stub().fetch(codes, http)
+    }
+}
+
+#[test]
+fn a_panicking_refresh_does_not_stay_in_flight() {
+    // the first refresh panics, app_with waits for it to end
+    let (mut app, _path) = app_with(&["0600000"], Arc::new(PanicsOnce(AtomicBool::new(false))));
+    assert!(!app.is_refreshing());
+    assert!(app.retry.lock().unwrap().is_empty());
+    app.force_refresh();
+    wait(&app);
+    assert_eq!(app.stocks.lock().unwrap()[0].price, 7.5);
+    assert!(render(&mut app).iter().any(|line| line.contains("PUFA")));
+}
+
+#[test]
+fn import_and_export_take_the_options_of_the_command_line() {
+    let (mut app, path) = app_with(&["0600000"], stub());
+    let file = path.with_file_name("holdings.csv");
+    fs::write(&file, "Stock No,Units\nsz000001,300\n").unwrap();
+    type_in(&mut app, 'i', &format!("{} --map code=Stock No,quantity=Units", file.display()));
+    key(&mut app, KeyCode::Enter);
+    key(&mut app, KeyCode::Enter);
+    wait(&app);
+    assert_eq!(codes_in(&path), vec!["0600000", "1000001"]);
+    assert_eq!(store::load(&path).unwrap()[1].quantity, Some(300.0));
+
+    let file = path.with_file_name("codes.csv");
+    type_in(&mut app, 'e', &format!("{} --format csv", file.display()));
+    key(&mut app, KeyCode::Enter);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "0600000\n1000001\n");
+}
+
+#[test]
+fn save_errors_show_in_the_title_bar() {
+    let (mut app, path) = app_with(&["0600000", "1000001"], stub());
+    // a directory in place of the data file cannot be read nor written
+    fs::remove_file(&*path).unwrap();
+    fs::create_dir(&*path).unwrap();
+    key(&mut app, KeyCode::Down);
+    key(&mut app, KeyCode::Char('d'));
+    assert_eq!(app.stocks.lock().unwrap().len(), 1);
+    let error = app.error.lock().unwrap().clone();
+    assert!(error.contains("cannot access"), "{}", error);
+}
+
+#[test]
+fn ticks_fade_out_price_changes() {
+    let (mut app, _path) = app_with(&["0600000"], stub());
+    app.stocks.lock().unwrap()[0].update_price(7.6, 2);
+    assert_eq!(app.stocks.lock().unwrap()[0].direction(), 1);
+    events::on_tick(&mut app);
+    events::on_tick(&mut app);
+    assert_eq!(app.stocks.lock().unwrap()[0].flash, 0);
+}
+
+#[test]
+fn q_exits() {
+    let (mut app, _path) = app_with(&[], stub());
+    key(&mut app, KeyCode::Char('q'));
+    assert!(app.should_exit);
+}
+
+#[test]
+fn a_recorded_session_replays_to_the_same_screen() {
+    let (_, path) = app_with(&["0600000", "1000001", "0601318"], stub());
+    let file = path.with_file_name("session.jsonl");
+    let recorder = Arc::new(Recorder::create(&file).unwrap());
+    recorder.record(Step::Start(store::load(&path).unwrap()));
+    recorder.record(Step::Clock((path.config.clock)()));
+    let mut app = App::try_with(path.config.clone(), Arc::new(Recording::new(stub(), recorder.clone()))).unwrap();
+    wait(&app);
+    let session = [
+        Event::Key(KeyEvent::new(KeyCode::Down, KeyModifiers::NONE)),
+        Event::Key(KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE)),
+        Event::Key(KeyEvent::new(KeyCode::Down, KeyModifiers::NONE)),
+    ];
+    for event in session {
+        recorder.record(Step::Event(event.clone()));
+        events::on_events(event, &mut app);
+    }
+    recorder.record(Step::Tick);
+    events::on_tick(&mut app);
+    wait(&app);
+    let recorded = render(&mut app);
+
+    let records = replay::load(&file).unwrap();
+    // the replay runs on the recorded clock, not on the one of its scratch dir
+    let scratch = Scratch::new();
+    let clock = ReplayClock::new(replay::start_time(&records).unwrap());
+    let config = Config { clock: clock.clock(), ..scratch.config.clone() };
+    store::save(&scratch, &replay::start_entries(&records)).unwrap();
+    let mut replayed = App::try_with(config, Arc::new(Replay::new(&records))).unwrap();
+    wait(&replayed);
+    for record in records.iter() {
+        clock.set(record.at);
+        replay::apply(&mut replayed, &record.step);
+    }
+    wait(&replayed);
+    assert_eq!(replayed.selected, app.selected);
+    assert_eq!(replayed.now().date_naive(), app.now().date_naive());
+    assert_eq!(render(&mut replayed), recorded);
+}
+
+#[test]
+fn a_bad_holidays_file_shows_until_the_first_key() {
+    let scratch = Scratch::new();
+    fs::write(&scratch.config.holidays_path, r#"{"holidays":["2024-10-01","tomorrow"]}"#).unwrap();
+    let mut app = App::try_with(scratch.config.clone(), stub()).unwrap();
+    wait(&app);
+    assert!(app.calendar.holidays.len() == 1 && app.notice.contains("bad holidays \"tomorrow\""), "{}", app.notice);
+    assert!(render(&mut app).iter().any(|line| line.contains("HOLIDAYS:")));
+    key(&mut app, KeyCode::Down);
+    assert!(app.notice.is_empty());
+}